@@ -224,12 +224,14 @@ type alias MediaStatsResponse =
 
 type Role
     = RoleUser
+    | RoleEditor
     | RoleAdmin
 
 
 roleVariants : List Role
 roleVariants =
     [ RoleUser
+    , RoleEditor
     , RoleAdmin
     ]
 
@@ -619,6 +621,9 @@ stringFromRole model =
         RoleUser ->
             "User"
 
+        RoleEditor ->
+            "Editor"
+
         RoleAdmin ->
             "Admin"
 
@@ -1073,6 +1078,9 @@ roleDecoder =
                     "User" ->
                         Json.Decode.succeed RoleUser
 
+                    "Editor" ->
+                        Json.Decode.succeed RoleEditor
+
                     "Admin" ->
                         Json.Decode.succeed RoleAdmin
 
@@ -6,7 +6,7 @@ import Forms.Fields exposing (emailField, nameField, passwordField)
 import Forms.Validators exposing (ValidationField, emailValidator, nameValidator, passwordValidator)
 import I18Next exposing (Translations)
 import Translations.Buttons exposing (newUser)
-import Translations.Forms exposing (edit, role, roleAdmin, roleUser)
+import Translations.Forms exposing (edit, role, roleAdmin, roleEditor, roleUser)
 import UI.Card exposing (viewForm)
 import UI.Checkbox exposing (CheckBox, viewCheckBox)
 import Uuid exposing (Uuid)
@@ -79,6 +79,9 @@ stringFromRole translations model =
         RoleUser ->
             roleUser translations
 
+        RoleEditor ->
+            roleEditor translations
+
         RoleAdmin ->
             roleAdmin translations
 
@@ -88,6 +91,9 @@ roleFromString translations role =
     if roleAdmin translations == role then
         RoleAdmin
 
+    else if roleEditor translations == role then
+        RoleEditor
+
     else
         RoleUser
 
@@ -125,7 +125,7 @@ tabs shared route user =
             , { title = users shared.translations, selected = route.path == Route.Path.Users, route = Route.Path.Users }
             ]
 
-        RoleUser ->
+        _ ->
             [ { title = home shared.translations, selected = route.path == Route.Path.Home_, route = Route.Path.Home_ }
             , { title = terms shared.translations, selected = route.path == Route.Path.Terms, route = Route.Path.Terms }
             , { title = snippets shared.translations, selected = route.path == Route.Path.Snippets, route = Route.Path.Snippets }
@@ -312,22 +312,25 @@ viewSnippets shared model =
 
                 common : List (Element Msg)
                 common =
-                    List.map (viewSnippet shared (model.session.role == RoleAdmin)) response.snippets
+                    List.map (viewSnippet shared (model.session.role /= RoleUser)) response.snippets
                         ++ [ viewPagination model.currentPage response.pages ]
             in
             case ( model.session.role, model.toUpdate, model.toDelete ) of
-                ( RoleAdmin, _, Just _ ) ->
+                ( RoleUser, _, _ ) ->
+                    termButton :: common
+
+                ( _, _, Just _ ) ->
                     [ defaultDialog shared.translations ClickedCancelDelete ClickedSubmitDelete ]
 
-                ( RoleAdmin, _, _ ) ->
+                _ ->
                     termButton :: defaultButton (newSnippet shared.translations) ClickedNew :: common
 
-                ( RoleUser, _, _ ) ->
-                    termButton :: common
-
         LoadedEdit ( terms, authors ) ->
             case ( model.session.role, model.toUpdate ) of
-                ( RoleAdmin, Just toUpdate ) ->
+                ( RoleUser, _ ) ->
+                    []
+
+                ( _, Just toUpdate ) ->
                     [ editSnippet shared toUpdate model authors terms ]
 
                 _ ->
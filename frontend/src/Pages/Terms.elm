@@ -209,18 +209,18 @@ viewTerms user shared model =
 
         Loaded terms ->
             case ( user.role, model.toUpdate, model.toDelete ) of
-                ( RoleAdmin, Just toUpdate, _ ) ->
+                ( RoleUser, _, _ ) ->
+                    List.map (viewTerm shared False terms) terms
+
+                ( _, Just toUpdate, _ ) ->
                     [ editTerm shared toUpdate model terms ]
 
-                ( RoleAdmin, _, Just _ ) ->
+                ( _, _, Just _ ) ->
                     [ defaultDialog shared.translations ClickedCancelDelete ClickedSubmitDelete ]
 
-                ( RoleAdmin, _, _ ) ->
+                _ ->
                     defaultButton (newTerm shared.translations) ClickedNew :: List.map (viewTerm shared True terms) terms
 
-                ( RoleUser, _, _ ) ->
-                    List.map (viewTerm shared False terms) terms
-
         Errored reason ->
             [ text (onError shared.translations reason) ]
 
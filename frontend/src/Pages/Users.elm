@@ -199,11 +199,11 @@ viewUser shared user =
         rightLabel : String
         rightLabel =
             case user.role of
-                RoleAdmin ->
-                    stringFromRole shared.translations user.role
+                RoleUser ->
+                    ""
 
                 _ ->
-                    ""
+                    stringFromRole shared.translations user.role
 
         buttons : List (Element Msg)
         buttons =
@@ -102,6 +102,11 @@ roleAdmin translations =
     t translations "forms.role_admin"
 
 
+roleEditor : Translations -> String
+roleEditor translations =
+    t translations "forms.role_editor"
+
+
 roleUser : Translations -> String
 roleUser translations =
     t translations "forms.role_user"
@@ -0,0 +1,13 @@
+CREATE TABLE refresh_tokens (
+   id UUID PRIMARY KEY,
+   user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+   family_id UUID NOT NULL,
+   token_hash TEXT NOT NULL UNIQUE,
+   expires_at TIMESTAMP NOT NULL,
+   used_at TIMESTAMP NULL,
+   revoked_at TIMESTAMP NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON refresh_tokens (user_id);
+CREATE INDEX ON refresh_tokens (family_id);
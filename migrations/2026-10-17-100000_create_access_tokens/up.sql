@@ -0,0 +1,11 @@
+CREATE TABLE access_tokens (
+   jti UUID PRIMARY KEY,
+   user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+   family_id UUID NOT NULL,
+   expires_at TIMESTAMP NOT NULL,
+   revoked_at TIMESTAMP NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON access_tokens (user_id);
+CREATE INDEX ON access_tokens (family_id);
@@ -0,0 +1,10 @@
+CREATE TABLE password_resets (
+   id UUID PRIMARY KEY,
+   user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+   token_hash TEXT NOT NULL UNIQUE,
+   expires_at TIMESTAMP NOT NULL,
+   used_at TIMESTAMP NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON password_resets (user_id);
@@ -0,0 +1,2 @@
+DROP TABLE login_attempts;
+DROP TYPE login_subject_enum;
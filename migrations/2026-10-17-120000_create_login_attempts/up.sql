@@ -0,0 +1,10 @@
+CREATE TYPE login_subject_enum AS ENUM ('email', 'ip');
+
+CREATE TABLE login_attempts (
+   subject login_subject_enum NOT NULL,
+   identifier VARCHAR NOT NULL,
+   failures INTEGER NOT NULL DEFAULT 0,
+   last_failed_at TIMESTAMP NOT NULL DEFAULT NOW(),
+   locked_until TIMESTAMP NULL,
+   PRIMARY KEY (subject, identifier)
+);
@@ -0,0 +1,14 @@
+CREATE TABLE api_keys (
+   id UUID PRIMARY KEY,
+   user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+   name VARCHAR NOT NULL,
+   prefix VARCHAR NOT NULL,
+   key_hash TEXT NOT NULL UNIQUE,
+   role role_enum NOT NULL,
+   expires_at TIMESTAMP NULL,
+   last_used_at TIMESTAMP NULL,
+   revoked_at TIMESTAMP NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON api_keys (user_id);
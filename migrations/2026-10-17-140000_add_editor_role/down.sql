@@ -0,0 +1,7 @@
+ALTER TYPE role_enum RENAME TO role_enum_old;
+CREATE TYPE role_enum AS ENUM ('user', 'admin');
+ALTER TABLE users ALTER COLUMN role TYPE role_enum
+   USING (CASE WHEN role = 'editor' THEN 'user' ELSE role::text END)::role_enum;
+ALTER TABLE api_keys ALTER COLUMN role TYPE role_enum
+   USING (CASE WHEN role = 'editor' THEN 'user' ELSE role::text END)::role_enum;
+DROP TYPE role_enum_old;
@@ -0,0 +1,6 @@
+-- Recreating the type instead of `ADD VALUE`, which can not be used in the transaction adding it
+ALTER TYPE role_enum RENAME TO role_enum_old;
+CREATE TYPE role_enum AS ENUM ('user', 'editor', 'admin');
+ALTER TABLE users ALTER COLUMN role TYPE role_enum USING role::text::role_enum;
+ALTER TABLE api_keys ALTER COLUMN role TYPE role_enum USING role::text::role_enum;
+DROP TYPE role_enum_old;
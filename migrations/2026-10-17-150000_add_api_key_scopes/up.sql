@@ -0,0 +1,5 @@
+ALTER TABLE api_keys ADD COLUMN scopes TEXT[] NOT NULL DEFAULT ARRAY[
+   'snippets:read', 'snippets:write', 'terms:read', 'terms:write', 'authors:read', 'users:read', 'users:write'
+];
+
+ALTER TABLE api_keys ALTER COLUMN scopes DROP DEFAULT;
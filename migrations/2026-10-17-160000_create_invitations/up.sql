@@ -0,0 +1,13 @@
+CREATE TABLE invitations (
+   id UUID PRIMARY KEY,
+   email VARCHAR NOT NULL,
+   role role_enum NOT NULL,
+   token_hash TEXT NOT NULL UNIQUE,
+   invited_by UUID NULL REFERENCES users (id) ON DELETE SET NULL,
+   expires_at TIMESTAMP NOT NULL,
+   accepted_at TIMESTAMP NULL,
+   revoked_at TIMESTAMP NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON invitations (email);
@@ -0,0 +1,28 @@
+CREATE TABLE totp_secrets (
+   user_id UUID PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+   secret BYTEA NOT NULL,
+   last_used_step BIGINT NULL,
+   confirmed_at TIMESTAMP NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE recovery_codes (
+   id UUID PRIMARY KEY,
+   user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+   code_hash TEXT NOT NULL UNIQUE,
+   used_at TIMESTAMP NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON recovery_codes (user_id);
+
+CREATE TABLE login_challenges (
+   id UUID PRIMARY KEY,
+   user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+   token_hash TEXT NOT NULL UNIQUE,
+   expires_at TIMESTAMP NOT NULL,
+   used_at TIMESTAMP NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON login_challenges (user_id);
@@ -0,0 +1,18 @@
+CREATE TABLE oidc_logins (
+   id UUID PRIMARY KEY,
+   state_hash TEXT NOT NULL UNIQUE,
+   nonce TEXT NOT NULL,
+   code_verifier TEXT NOT NULL,
+   expires_at TIMESTAMP NOT NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE user_identities (
+   issuer TEXT NOT NULL,
+   subject TEXT NOT NULL,
+   user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+   PRIMARY KEY (issuer, subject)
+);
+
+CREATE INDEX ON user_identities (user_id);
@@ -0,0 +1,14 @@
+CREATE TABLE sessions (
+   id UUID PRIMARY KEY,
+   user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+   ip TEXT NULL,
+   user_agent TEXT NULL,
+   created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON sessions (user_id);
+
+INSERT INTO sessions (id, user_id, created_at)
+SELECT family_id, user_id, MIN(created_at)
+FROM refresh_tokens
+GROUP BY family_id, user_id;
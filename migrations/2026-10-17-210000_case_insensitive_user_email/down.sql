@@ -0,0 +1,3 @@
+DROP INDEX users_email_key;
+
+CREATE UNIQUE INDEX users_email_key ON users (email) WHERE NOT is_deleted;
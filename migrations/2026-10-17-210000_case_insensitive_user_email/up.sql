@@ -0,0 +1,25 @@
+-- Emails that only differ in case, surrounding whitespace or Unicode composition belong to the
+-- same person. Such accounts have to be merged or renamed by hand before migrating.
+DO $$
+DECLARE
+   collisions TEXT;
+BEGIN
+   SELECT string_agg(format('%s (%s)', email, ids), '; ')
+   INTO collisions
+   FROM (
+      SELECT lower(normalize(trim(email), NFC)) AS email, string_agg(id::TEXT, ', ') AS ids
+      FROM users
+      WHERE NOT is_deleted
+      GROUP BY 1
+      HAVING count(*) > 1
+   ) AS colliding;
+   IF collisions IS NOT NULL THEN
+      RAISE EXCEPTION 'Users share an email when compared case-insensitively: %', collisions;
+   END IF;
+END $$;
+
+DROP INDEX users_email_key;
+
+UPDATE users SET email = normalize(trim(email), NFC) WHERE email <> normalize(trim(email), NFC);
+
+CREATE UNIQUE INDEX users_email_key ON users (lower(email)) WHERE NOT is_deleted;
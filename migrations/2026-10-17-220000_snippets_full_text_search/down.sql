@@ -0,0 +1,4 @@
+DROP INDEX snippets_text_search_idx;
+
+ALTER TABLE snippets DROP COLUMN text_search;
+ALTER TABLE snippets DROP COLUMN search_language;
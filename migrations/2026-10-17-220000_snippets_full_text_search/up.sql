@@ -0,0 +1,7 @@
+-- The configuration is kept per row so the generated column stays immutable, the application
+-- sets it to SEARCH_LANGUAGE on start and on every write.
+ALTER TABLE snippets ADD COLUMN search_language REGCONFIG NOT NULL DEFAULT 'english';
+ALTER TABLE snippets ADD COLUMN text_search TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector(search_language, text)) STORED;
+
+CREATE INDEX snippets_text_search_idx ON snippets USING GIN (text_search);
@@ -0,0 +1,4 @@
+DROP TRIGGER set_updated_at ON snippets;
+DROP FUNCTION snippets_set_updated_at();
+
+SELECT diesel_manage_updated_at('snippets');
@@ -0,0 +1,17 @@
+-- Stemming the snippets again in another search language does not change them
+CREATE FUNCTION snippets_set_updated_at() RETURNS trigger AS $$
+BEGIN
+    IF (
+        to_jsonb(NEW) - 'search_language' - 'text_search' IS DISTINCT FROM
+            to_jsonb(OLD) - 'search_language' - 'text_search' AND
+        NEW.updated_at IS NOT DISTINCT FROM OLD.updated_at
+    ) THEN
+        NEW.updated_at := current_timestamp;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER set_updated_at ON snippets;
+CREATE TRIGGER set_updated_at BEFORE UPDATE ON snippets
+    FOR EACH ROW EXECUTE PROCEDURE snippets_set_updated_at();
@@ -0,0 +1 @@
+DROP INDEX snippets_created_at_id_idx;
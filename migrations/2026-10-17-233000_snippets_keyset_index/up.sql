@@ -0,0 +1 @@
+CREATE INDEX snippets_created_at_id_idx ON snippets (created_at DESC, id DESC);
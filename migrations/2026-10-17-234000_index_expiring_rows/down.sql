@@ -0,0 +1,5 @@
+DROP INDEX oidc_logins_expires_at_idx;
+DROP INDEX login_challenges_expires_at_idx;
+DROP INDEX password_resets_expires_at_idx;
+DROP INDEX refresh_tokens_expires_at_idx;
+DROP INDEX access_tokens_expires_at_idx;
@@ -0,0 +1,6 @@
+-- Expired rows are deleted when new ones are inserted
+CREATE INDEX ON access_tokens (expires_at);
+CREATE INDEX ON refresh_tokens (expires_at);
+CREATE INDEX ON password_resets (expires_at);
+CREATE INDEX ON login_challenges (expires_at);
+CREATE INDEX ON oidc_logins (expires_at);
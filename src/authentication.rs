@@ -1,18 +1,51 @@
-use crate::config::hasher_salt;
+use crate::config::{argon2_iterations, argon2_memory_kib, argon2_parallelism, hasher_salt};
 use crate::error::Error;
 
-use argon2::password_hash::SaltString;
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{Salt, SaltString};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+fn params() -> Result<Params, Error> {
+    Params::new(
+        argon2_memory_kib(),
+        argon2_iterations(),
+        argon2_parallelism(),
+        None,
+    )
+    .map_err(Error::from)
+}
 
 pub fn hash_password(password: &str) -> Result<String, Error> {
-    let argon2 = Argon2::default();
-    let salt = SaltString::from_b64(&hasher_salt())?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params()?);
+    let mut bytes = [0u8; Salt::RECOMMENDED_LENGTH];
+    rand::rng().fill_bytes(&mut bytes);
+    let salt = SaltString::encode_b64(&bytes)?;
     let hash = argon2
         .hash_password(password.as_ref(), &salt)
         .map_err(Error::from)?;
     Ok(hash.to_string())
 }
 
+/// Whether a stored hash predates per-password salts or was made with other Argon2 parameters.
+pub fn needs_rehash(hash: &str) -> Result<bool, Error> {
+    let parsed_hash = PasswordHash::new(hash)?;
+    let legacy_salt = hasher_salt();
+    if parsed_hash.salt.map(|salt| salt.as_str()) == legacy_salt.as_deref() {
+        return Ok(true);
+    }
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident() {
+        return Ok(true);
+    }
+    let current = params()?;
+    let used = Params::try_from(&parsed_hash)?;
+    Ok(used.m_cost() != current.m_cost()
+        || used.t_cost() != current.t_cost()
+        || used.p_cost() != current.p_cost())
+}
+
 pub fn verify_password(password: &str, hash: &str) -> Result<(), Error> {
     let argon2 = Argon2::default();
     let parsed_hash = PasswordHash::new(hash)?;
@@ -21,6 +54,16 @@ pub fn verify_password(password: &str, hash: &str) -> Result<(), Error> {
         .map_err(Error::from)
 }
 
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -41,4 +84,57 @@ mod tests {
         let hash = hash_password(&password).expect("Can not hash password");
         verify_password(&password, &hash).expect("Can not verify password");
     }
+
+    #[test]
+    fn test_same_password_gets_different_salts() {
+        load_and_validate_env_vars();
+
+        let first = hash_password("password").expect("Can not hash password");
+        let second = hash_password("password").expect("Can not hash password");
+        assert_ne!(first, second);
+        assert!(!needs_rehash(&first).expect("Can not check hash"));
+    }
+
+    #[test]
+    fn test_legacy_hash_needs_rehash() {
+        load_and_validate_env_vars();
+
+        let salt = SaltString::from_b64(&hasher_salt().expect("HASHER_SALT must be set"))
+            .expect("Can not parse salt");
+        let legacy = Argon2::default()
+            .hash_password("password".as_ref(), &salt)
+            .expect("Can not hash password")
+            .to_string();
+        assert!(needs_rehash(&legacy).expect("Can not check hash"));
+        verify_password("password", &legacy).expect("Can not verify password");
+    }
+
+    #[test]
+    fn test_outdated_params_need_rehash() {
+        load_and_validate_env_vars();
+
+        let params = Params::new(Params::MIN_M_COST * 8, 1, 1, None).expect("Invalid params");
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let salt = SaltString::encode_b64(b"0123456789abcdef").expect("Can not encode salt");
+        let hash = argon2
+            .hash_password("password".as_ref(), &salt)
+            .expect("Can not hash password")
+            .to_string();
+        assert!(needs_rehash(&hash).expect("Can not check hash"));
+    }
+
+    #[test]
+    fn test_generated_tokens_are_unique() {
+        let first = generate_token();
+        let second = generate_token();
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 43);
+    }
+
+    #[test]
+    fn test_hash_token_is_stable() {
+        let token = generate_token();
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_ne!(hash_token(&token), token);
+    }
 }
@@ -1,3 +1,5 @@
+use crate::models::enums::Role;
+
 use dotenv::dotenv;
 use std::collections::HashMap;
 use std::env;
@@ -7,10 +9,30 @@ pub fn load_and_validate_env_vars() {
     let result: HashMap<String, String> = dotenv::vars().collect();
     assert!(result.contains_key("DATABASE_URL"));
     assert!(result.contains_key("APP_URL"));
-    assert!(result.contains_key("JWT_SECRET"));
-    assert!(result.contains_key("HASHER_SALT"));
     assert!(result.contains_key("ADMIN_EMAIL"));
     assert!(result.contains_key("ADMIN_PASSWORD"));
+    if let Some(role) = result.get("OIDC_PROVISION_ROLE") {
+        assert!(
+            parse_role(role).is_some(),
+            "OIDC_PROVISION_ROLE must be User, Editor or Admin"
+        );
+    }
+    // Debug builds fall back to a temporary key and to logging messages
+    if !cfg!(debug_assertions) {
+        assert!(
+            result.contains_key("JWT_SIGNING_KEY"),
+            "JWT_SIGNING_KEY must be set"
+        );
+        assert!(
+            result.contains_key("TOTP_ENCRYPTION_KEY"),
+            "TOTP_ENCRYPTION_KEY must be set"
+        );
+        // Reset links and invitation tokens do not belong into the log
+        assert!(
+            result.contains_key("SMTP_URL") || result.contains_key("MAIL_DIR"),
+            "SMTP_URL or MAIL_DIR must be set"
+        );
+    }
 }
 
 pub fn app_address() -> String {
@@ -27,12 +49,59 @@ pub fn app_url() -> String {
     env::var("APP_URL").expect("APP_URL must be set")
 }
 
-pub fn jwt_secret() -> String {
-    env::var("JWT_SECRET").expect("JWT_SECRET must be set")
+/// Base64 encoded PKCS#8 Ed25519 key, e.g. `openssl genpkey -algorithm ed25519 -outform DER | base64`.
+pub fn jwt_signing_key() -> Option<String> {
+    env::var("JWT_SIGNING_KEY").ok()
+}
+
+/// Comma separated public keys of retired signing keys, as the `x` of their JWKS entry.
+pub fn jwt_verification_keys() -> Vec<String> {
+    env::var("JWT_VERIFICATION_KEYS")
+        .map(|keys| {
+            keys.split(',')
+                .filter(|key| !key.trim().is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Global salt of hashes made before passwords got their own, only needed to recognise those.
+pub fn hasher_salt() -> Option<String> {
+    env::var("HASHER_SALT").ok()
+}
+
+/// Base64 encoded 32 byte key encrypting the TOTP secrets, e.g. `openssl rand -base64 32`.
+pub fn totp_encryption_key() -> Option<String> {
+    env::var("TOTP_ENCRYPTION_KEY").ok()
+}
+
+/// Admins without two-factor authentication have to enrol the next time they sign in.
+pub fn require_admin_2fa() -> bool {
+    let required = env::var("REQUIRE_ADMIN_2FA").unwrap_or_else(|_| "false".to_string());
+    required.parse::<bool>().unwrap()
 }
 
-pub fn hasher_salt() -> String {
-    env::var("HASHER_SALT").expect("HASHER_SALT must be set")
+/// Keeps the JWT and refresh token in HttpOnly cookies for the web frontend, the bearer header is
+/// still accepted.
+pub fn cookie_auth() -> bool {
+    let enabled = env::var("COOKIE_AUTH").unwrap_or_else(|_| "false".to_string());
+    enabled.parse::<bool>().unwrap()
+}
+
+pub fn argon2_memory_kib() -> u32 {
+    let memory = env::var("ARGON2_MEMORY_KIB").unwrap_or_else(|_| "19456".to_string());
+    memory.parse::<u32>().unwrap()
+}
+
+pub fn argon2_iterations() -> u32 {
+    let iterations = env::var("ARGON2_ITERATIONS").unwrap_or_else(|_| "2".to_string());
+    iterations.parse::<u32>().unwrap()
+}
+
+pub fn argon2_parallelism() -> u32 {
+    let parallelism = env::var("ARGON2_PARALLELISM").unwrap_or_else(|_| "1".to_string());
+    parallelism.parse::<u32>().unwrap()
 }
 
 pub fn admin_email() -> String {
@@ -48,6 +117,98 @@ pub fn db_pool_size() -> u32 {
     size.parse::<u32>().unwrap()
 }
 
+pub fn refresh_token_ttl_days() -> i64 {
+    let days = env::var("REFRESH_TOKEN_TTL_DAYS").unwrap_or_else(|_| "30".to_string());
+    days.parse::<i64>().unwrap()
+}
+
+pub fn password_reset_ttl_minutes() -> i64 {
+    let minutes = env::var("PASSWORD_RESET_TTL_MINUTES").unwrap_or_else(|_| "60".to_string());
+    minutes.parse::<i64>().unwrap()
+}
+
+pub fn invitation_ttl_hours() -> i64 {
+    let hours = env::var("INVITATION_TTL_HOURS").unwrap_or_else(|_| "72".to_string());
+    hours.parse::<i64>().unwrap()
+}
+
+/// Signing in through an OpenID Connect provider is only offered when its issuer is set.
+pub fn oidc_issuer() -> Option<String> {
+    env::var("OIDC_ISSUER").ok()
+}
+
+pub fn oidc_client_id() -> String {
+    env::var("OIDC_CLIENT_ID").unwrap_or_else(|_| "unpacking".to_string())
+}
+
+/// Not needed by public clients, PKCE protects the code exchange.
+pub fn oidc_client_secret() -> Option<String> {
+    env::var("OIDC_CLIENT_SECRET").ok()
+}
+
+pub fn oidc_scopes() -> String {
+    env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid email profile".to_string())
+}
+
+/// The page of the frontend that receives the authorization code.
+pub fn oidc_redirect_url() -> String {
+    env::var("OIDC_REDIRECT_URL").unwrap_or_else(|_| format!("{}/oidc/callback", app_url()))
+}
+
+/// Role of accounts created on their first sign in, without it only existing users can sign in.
+pub fn oidc_provision_role() -> Option<Role> {
+    env::var("OIDC_PROVISION_ROLE")
+        .ok()
+        .map(|role| parse_role(&role).expect("OIDC_PROVISION_ROLE must be User, Editor or Admin"))
+}
+
+fn parse_role(role: &str) -> Option<Role> {
+    match role {
+        "User" => Some(Role::User),
+        "Editor" => Some(Role::Editor),
+        "Admin" => Some(Role::Admin),
+        _ => None,
+    }
+}
+
+/// Header a trusted proxy puts the client address in, e.g. `Fly-Client-IP`. Without it the peer
+/// address is used, which behind a proxy is the same for every client.
+pub fn client_ip_header() -> Option<String> {
+    env::var("CLIENT_IP_HEADER").ok()
+}
+
+pub fn login_lockout_threshold() -> i32 {
+    let threshold = env::var("LOGIN_LOCKOUT_THRESHOLD").unwrap_or_else(|_| "5".to_string());
+    threshold.parse::<i32>().unwrap()
+}
+
+pub fn login_lockout_minutes() -> i64 {
+    let minutes = env::var("LOGIN_LOCKOUT_MINUTES").unwrap_or_else(|_| "15".to_string());
+    minutes.parse::<i64>().unwrap()
+}
+
+pub fn login_backoff_seconds() -> i64 {
+    let seconds = env::var("LOGIN_BACKOFF_SECONDS").unwrap_or_else(|_| "1".to_string());
+    seconds.parse::<i64>().unwrap()
+}
+
+pub fn smtp_url() -> Option<String> {
+    env::var("SMTP_URL").ok()
+}
+
+pub fn mail_from() -> String {
+    env::var("MAIL_FROM").unwrap_or_else(|_| "noreply@localhost".to_string())
+}
+
+pub fn mail_dir() -> Option<String> {
+    env::var("MAIL_DIR").ok()
+}
+
+/// Postgres text search configuration used to stem snippets and queries, e.g. `german`.
+pub fn search_language() -> String {
+    env::var("SEARCH_LANGUAGE").unwrap_or_else(|_| "english".to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,6 +220,12 @@ mod tests {
         });
     }
 
+    #[test]
+    fn provision_role_is_parsed() {
+        assert_eq!(parse_role("Editor"), Some(Role::Editor));
+        assert_eq!(parse_role("editor"), None);
+    }
+
     #[test]
     fn app_address_from_env() {
         temp_env::with_vars(
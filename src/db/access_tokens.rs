@@ -0,0 +1,96 @@
+use crate::error::Error;
+use crate::models::AccessToken;
+use crate::schema::access_tokens;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl,
+    RunQueryDsl,
+};
+use uuid::Uuid;
+
+pub fn insert(
+    user_id: Uuid,
+    family_id: Uuid,
+    expires_at: NaiveDateTime,
+    conn: &mut PgConnection,
+) -> Result<Uuid, Error> {
+    prune(conn)?;
+    let token = AccessToken::new(user_id, family_id, expires_at);
+    diesel::insert_into(access_tokens::dsl::access_tokens)
+        .values(token)
+        .returning(access_tokens::dsl::jti)
+        .get_result(conn)
+        .map_err(Error::from)
+}
+
+/// Expired tokens fail their `exp` check before their row is looked up.
+fn prune(conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::delete(access_tokens::dsl::access_tokens)
+        .filter(access_tokens::dsl::expires_at.lt(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+pub fn is_active(jti: Uuid, conn: &mut PgConnection) -> Result<bool, Error> {
+    let found = access_tokens::dsl::access_tokens
+        .filter(
+            access_tokens::dsl::jti
+                .eq(jti)
+                .and(access_tokens::dsl::revoked_at.is_null()),
+        )
+        .select(access_tokens::dsl::jti)
+        .first::<Uuid>(conn)
+        .optional()
+        .map_err(Error::from)?;
+    Ok(found.is_some())
+}
+
+pub fn revoke(jti: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::update(access_tokens::dsl::access_tokens.find(jti))
+        .filter(access_tokens::dsl::revoked_at.is_null())
+        .set(access_tokens::dsl::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+pub fn revoke_family(family_id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::update(access_tokens::dsl::access_tokens)
+        .filter(
+            access_tokens::dsl::family_id
+                .eq(family_id)
+                .and(access_tokens::dsl::revoked_at.is_null()),
+        )
+        .set(access_tokens::dsl::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+pub fn revoke_all(user_id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::update(access_tokens::dsl::access_tokens)
+        .filter(
+            access_tokens::dsl::user_id
+                .eq(user_id)
+                .and(access_tokens::dsl::revoked_at.is_null()),
+        )
+        .set(access_tokens::dsl::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+pub fn revoke_others(
+    user_id: Uuid,
+    family_id: Uuid,
+    conn: &mut PgConnection,
+) -> Result<usize, Error> {
+    diesel::update(access_tokens::dsl::access_tokens)
+        .filter(
+            access_tokens::dsl::user_id
+                .eq(user_id)
+                .and(access_tokens::dsl::family_id.ne(family_id))
+                .and(access_tokens::dsl::revoked_at.is_null()),
+        )
+        .set(access_tokens::dsl::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
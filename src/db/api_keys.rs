@@ -0,0 +1,167 @@
+use crate::error::Error;
+use crate::models::{enums::Role, ApiKey};
+use crate::schema::{api_keys, users};
+
+use chrono::Utc;
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    QueryDsl, RunQueryDsl,
+};
+use uuid::Uuid;
+
+/// Refuses keys with a higher role than their owner currently has.
+pub fn insert(key: ApiKey, conn: &mut PgConnection) -> Result<Uuid, Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        let owner_role = users::dsl::users
+            .find(key.user_id())
+            .filter(users::dsl::is_deleted.eq(false))
+            .select(users::dsl::role)
+            .first::<Role>(conn)?;
+        if *key.role() > owner_role {
+            return Err(Error::Forbidden);
+        }
+        diesel::insert_into(api_keys::dsl::api_keys)
+            .values(key)
+            .returning(api_keys::dsl::id)
+            .get_result(conn)
+            .map_err(Error::from)
+    })
+}
+
+pub fn select_all(user_id: Uuid, conn: &mut PgConnection) -> Result<Vec<ApiKey>, Error> {
+    api_keys::dsl::api_keys
+        .filter(
+            api_keys::dsl::user_id
+                .eq(user_id)
+                .and(api_keys::dsl::revoked_at.is_null()),
+        )
+        .order(api_keys::dsl::created_at)
+        .load(conn)
+        .map_err(Error::from)
+}
+
+pub fn revoke(user_id: Uuid, id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::update(api_keys::dsl::api_keys.find(id))
+        .filter(
+            api_keys::dsl::user_id
+                .eq(user_id)
+                .and(api_keys::dsl::revoked_at.is_null()),
+        )
+        .set(api_keys::dsl::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+/// Looks up an unrevoked and unexpired key of a non-deleted user and records its use. The key
+/// acts with the lower of its own role and the current role of its owner, so a demotion also
+/// applies to keys created before it.
+pub fn authenticate(key_hash: &str, conn: &mut PgConnection) -> Result<(ApiKey, Role), Error> {
+    let now = Utc::now().naive_utc();
+    let (key, owner_role) = api_keys::dsl::api_keys
+        .inner_join(users::table)
+        .filter(
+            api_keys::dsl::key_hash
+                .eq(key_hash)
+                .and(api_keys::dsl::revoked_at.is_null())
+                .and(users::dsl::is_deleted.eq(false)),
+        )
+        .select((api_keys::all_columns, users::dsl::role))
+        .first::<(ApiKey, Role)>(conn)
+        .optional()?
+        .ok_or(Error::Unauthorized)?;
+    if key.expires_at().is_some_and(|expires_at| expires_at < now) {
+        return Err(Error::Unauthorized);
+    }
+    diesel::update(api_keys::dsl::api_keys.find(key.id()))
+        .set(api_keys::dsl::last_used_at.eq(now))
+        .execute(conn)?;
+    let role = (*key.role()).min(owner_role);
+    Ok((key, role))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{generate_token, hash_token};
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::users;
+    use crate::models::{Password, User};
+    use chrono::{Duration, NaiveDateTime};
+    use fake::{Fake, Faker};
+
+    fn key(user_id: Uuid, role: Role, expires_at: Option<NaiveDateTime>) -> (ApiKey, String) {
+        let hash = hash_token(&generate_token());
+        let key = ApiKey::new(
+            user_id,
+            "Ingestion".to_string(),
+            "upk_test".to_string(),
+            hash.clone(),
+            role,
+            expires_at,
+            vec!["snippets:read".to_string()],
+        );
+        (key, hash)
+    }
+
+    #[tokio::test]
+    async fn role_can_not_exceed_owner() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let password: Password = Faker.fake();
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+            let (admin_key, _) = key(user_id, Role::Admin, None);
+            assert_eq!(insert(admin_key, conn), Err(Error::Forbidden));
+            let (user_key, _) = key(user_id, Role::User, None);
+            insert(user_key, conn).expect("Can not insert");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn demotion_applies_to_keys() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::Admin);
+            let name = user.name().clone();
+            let email = user.email().clone();
+            let password: Password = Faker.fake();
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+            let (admin_key, hash) = key(user_id, Role::Admin, None);
+            insert(admin_key, conn).expect("Can not insert");
+            let (_, role) = authenticate(&hash, conn).expect("Can not authenticate");
+            assert_eq!(role, Role::Admin);
+
+            users::update(user_id, name, email, Role::User, conn).expect("Can not update");
+            let (key, role) = authenticate(&hash, conn).expect("Can not authenticate");
+            assert_eq!(role, Role::User);
+            assert!(key.last_used_at().is_some());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn revoked_and_expired_keys_are_rejected() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let password: Password = Faker.fake();
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+            let expired = Some(Utc::now().naive_utc() - Duration::minutes(1));
+            let (expired_key, expired_hash) = key(user_id, Role::User, expired);
+            insert(expired_key, conn).expect("Can not insert");
+            assert_eq!(
+                authenticate(&expired_hash, conn).map(|_| ()),
+                Err(Error::Unauthorized)
+            );
+
+            let (valid_key, hash) = key(user_id, Role::User, None);
+            let id = insert(valid_key, conn).expect("Can not insert");
+            assert_eq!(select_all(user_id, conn).expect("Can not select").len(), 2);
+            assert_eq!(revoke(Uuid::new_v4(), id, conn), Ok(0));
+            assert_eq!(revoke(user_id, id, conn), Ok(1));
+            assert_eq!(
+                authenticate(&hash, conn).map(|_| ()),
+                Err(Error::Unauthorized)
+            );
+        })
+        .await;
+    }
+}
@@ -0,0 +1,146 @@
+use crate::db::login_attempts;
+use crate::error::Error;
+use crate::models::{
+    ApiKey, Invitation, LoginAttempt, PasswordReset, Session, TotpSecret, User, UserIdentity,
+};
+use crate::schema::{
+    api_keys, invitations, password_resets, recovery_codes, sessions, totp_secrets,
+    user_identities, users,
+};
+
+use derive_getters::Dissolve;
+use diesel::{
+    Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl,
+};
+use uuid::Uuid;
+
+/// Everything stored about a user. Snippets, terms and authors are shared and not attributed to
+/// the users who created them, so they are not part of it.
+#[derive(Dissolve)]
+pub struct UserData {
+    user: User,
+    sessions: Vec<Session>,
+    identities: Vec<UserIdentity>,
+    api_keys: Vec<ApiKey>,
+    invitations: Vec<Invitation>,
+    totp_secret: Option<TotpSecret>,
+    recovery_codes_left: i64,
+    password_resets: Vec<PasswordReset>,
+    login_attempt: Option<LoginAttempt>,
+}
+
+/// Includes deleted users, whose data is kept until they are purged, and revoked or expired
+/// sessions and keys.
+pub fn select(user_id: Uuid, conn: &mut PgConnection) -> Result<UserData, Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        let user = users::dsl::users.find(user_id).get_result::<User>(conn)?;
+        let sessions = sessions::dsl::sessions
+            .filter(sessions::dsl::user_id.eq(user_id))
+            .order(sessions::dsl::created_at)
+            .load(conn)?;
+        let identities = user_identities::dsl::user_identities
+            .filter(user_identities::dsl::user_id.eq(user_id))
+            .order(user_identities::dsl::created_at)
+            .load(conn)?;
+        let api_keys = api_keys::dsl::api_keys
+            .filter(api_keys::dsl::user_id.eq(user_id))
+            .order(api_keys::dsl::created_at)
+            .load(conn)?;
+        let invitations = invitations::dsl::invitations
+            .filter(invitations::dsl::invited_by.eq(user_id))
+            .order(invitations::dsl::created_at)
+            .load(conn)?;
+        let totp_secret = totp_secrets::dsl::totp_secrets
+            .find(user_id)
+            .first(conn)
+            .optional()?;
+        let recovery_codes_left = recovery_codes::dsl::recovery_codes
+            .filter(recovery_codes::dsl::user_id.eq(user_id))
+            .filter(recovery_codes::dsl::used_at.is_null())
+            .count()
+            .get_result(conn)?;
+        let password_resets = password_resets::dsl::password_resets
+            .filter(password_resets::dsl::user_id.eq(user_id))
+            .order(password_resets::dsl::created_at)
+            .load(conn)?;
+        let login_attempt = login_attempts::select_by_email(user.email(), conn)?;
+        Ok(UserData {
+            user,
+            sessions,
+            identities,
+            api_keys,
+            invitations,
+            totp_secret,
+            recovery_codes_left,
+            password_resets,
+            login_attempt,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::{
+        api_keys as api_keys_db, password_resets as password_resets_db, sessions as sessions_db,
+        users as users_db,
+    };
+    use crate::models::{enums::Role, Password};
+    use fake::{Fake, Faker};
+
+    #[tokio::test]
+    async fn deleted_users_are_exported() {
+        run_migrations_and_test_transactions(|conn| {
+            let password: Password = Faker.fake();
+            let [user_id, other_id] = [(); 2].map(|_| {
+                users_db::insert(User::fake(Role::User), password.password(), conn)
+                    .expect("Can not create")
+            });
+            for user_id in [user_id, other_id] {
+                let session = Session::new(Uuid::new_v4(), user_id, None, None);
+                sessions_db::insert(session, conn).expect("Can not insert");
+            }
+            let key = ApiKey::new(
+                user_id,
+                "Export".to_string(),
+                "prefix".to_string(),
+                "hash".to_string(),
+                Role::User,
+                None,
+                Vec::new(),
+            );
+            api_keys_db::insert(key, conn).expect("Can not insert");
+            password_resets_db::insert(user_id, "hash".to_string(), conn).expect("Can not insert");
+            let email = users_db::select(user_id, conn)
+                .expect("Can not select")
+                .email()
+                .clone();
+            login_attempts::record_failure(&email, None, conn).expect("Can not record");
+            users_db::delete(user_id, conn).expect("Can not delete");
+
+            let (
+                user,
+                sessions,
+                identities,
+                api_keys,
+                invitations,
+                totp_secret,
+                recovery_codes_left,
+                password_resets,
+                login_attempt,
+            ) = select(user_id, conn).expect("Can not export").dissolve();
+            assert_eq!(user.id(), &user_id);
+            assert_eq!(sessions.len(), 1);
+            assert_eq!(sessions[0].user_id(), &user_id);
+            assert!(identities.is_empty());
+            assert_eq!(api_keys.len(), 1);
+            assert!(invitations.is_empty());
+            assert!(totp_secret.is_none());
+            assert_eq!(recovery_codes_left, 0);
+            assert_eq!(password_resets.len(), 1);
+            assert_eq!(login_attempt.map(|attempt| *attempt.failures()), Some(1));
+        })
+        .await;
+    }
+}
@@ -0,0 +1,169 @@
+use crate::config::invitation_ttl_hours;
+use crate::db::users;
+use crate::error::Error;
+use crate::models::{enums::Role, Invitation, User};
+use crate::schema::invitations;
+
+use chrono::{Duration, Utc};
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    QueryDsl, RunQueryDsl,
+};
+use uuid::Uuid;
+
+/// Refuses to invite an email that already has an account.
+pub fn insert(
+    email: String,
+    role: Role,
+    token_hash: String,
+    invited_by: Uuid,
+    conn: &mut PgConnection,
+) -> Result<Uuid, Error> {
+    if users::select_by_email(&email, conn)?.is_some() {
+        return Err(Error::BadRequest("Already exists".to_string()));
+    }
+    let expires_at = Utc::now().naive_utc() + Duration::hours(invitation_ttl_hours());
+    let invitation = Invitation::new(email, role, token_hash, invited_by, expires_at);
+    diesel::insert_into(invitations::dsl::invitations)
+        .values(invitation)
+        .returning(invitations::dsl::id)
+        .get_result(conn)
+        .map_err(Error::from)
+}
+
+/// Invitations that are neither accepted, revoked nor expired.
+pub fn select_pending(conn: &mut PgConnection) -> Result<Vec<Invitation>, Error> {
+    invitations::dsl::invitations
+        .filter(
+            invitations::dsl::accepted_at
+                .is_null()
+                .and(invitations::dsl::revoked_at.is_null())
+                .and(invitations::dsl::expires_at.gt(Utc::now().naive_utc())),
+        )
+        .order(invitations::dsl::created_at)
+        .load(conn)
+        .map_err(Error::from)
+}
+
+pub fn revoke(id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::update(invitations::dsl::invitations.find(id))
+        .filter(
+            invitations::dsl::accepted_at
+                .is_null()
+                .and(invitations::dsl::revoked_at.is_null()),
+        )
+        .set(invitations::dsl::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+/// Creates the account of a pending invitation with the chosen name and password, and uses the
+/// invitation up.
+pub fn accept(
+    token_hash: &str,
+    name: String,
+    password: &str,
+    conn: &mut PgConnection,
+) -> Result<Uuid, Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        let invitation = invitations::dsl::invitations
+            .filter(invitations::dsl::token_hash.eq(token_hash))
+            .for_update()
+            .first::<Invitation>(conn)
+            .optional()?
+            .ok_or(Error::Unauthorized)?;
+        let now = Utc::now().naive_utc();
+        if invitation.accepted_at().is_some()
+            || invitation.revoked_at().is_some()
+            || *invitation.expires_at() < now
+        {
+            return Err(Error::Unauthorized);
+        }
+        diesel::update(invitations::dsl::invitations.find(invitation.id()))
+            .set(invitations::dsl::accepted_at.eq(now))
+            .execute(conn)?;
+        let (_, email, role, _, _, _, _, _, _) = invitation.dissolve();
+        users::insert(User::new(name, email, role), password, conn)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{generate_token, hash_token};
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::models::Password;
+    use fake::{Fake, Faker};
+
+    fn admin(conn: &mut PgConnection) -> Uuid {
+        let user = User::fake(Role::Admin);
+        let password: Password = Faker.fake();
+        users::insert(user, password.password(), conn).expect("Can not create")
+    }
+
+    #[tokio::test]
+    async fn accept_is_single_use() {
+        run_migrations_and_test_transactions(|conn| {
+            let invited_by = admin(conn);
+            let email = User::fake(Role::Editor).email().clone();
+            let token = hash_token(&generate_token());
+            insert(email.clone(), Role::Editor, token.clone(), invited_by, conn)
+                .expect("Can not insert");
+            assert_eq!(select_pending(conn).expect("Can not select").len(), 1);
+
+            let user_id =
+                accept(&token, "Invitee".to_string(), "password", conn).expect("Can not accept");
+            let (id, role) =
+                users::authenticate(&email, "password", conn).expect("Can not authenticate");
+            assert_eq!(id, user_id);
+            assert_eq!(role, Role::Editor);
+            assert!(select_pending(conn).expect("Can not select").is_empty());
+
+            let result = accept(&token, "Invitee".to_string(), "password", conn);
+            assert_eq!(result, Err(Error::Unauthorized));
+
+            let result = insert(
+                email,
+                Role::User,
+                hash_token(&generate_token()),
+                invited_by,
+                conn,
+            );
+            assert_eq!(result, Err(Error::BadRequest("Already exists".to_string())));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn revoked_and_expired_invitations_are_rejected() {
+        run_migrations_and_test_transactions(|conn| {
+            let invited_by = admin(conn);
+            let token = hash_token(&generate_token());
+            let email = User::fake(Role::User).email().clone();
+            let id =
+                insert(email, Role::User, token.clone(), invited_by, conn).expect("Can not insert");
+            assert_eq!(revoke(id, conn), Ok(1));
+            assert_eq!(revoke(id, conn), Ok(0));
+            let result = accept(&token, "Invitee".to_string(), "password", conn);
+            assert_eq!(result, Err(Error::Unauthorized));
+
+            let token = hash_token(&generate_token());
+            let email = User::fake(Role::User).email().clone();
+            let expires_at = Utc::now().naive_utc() - Duration::minutes(1);
+            diesel::insert_into(invitations::dsl::invitations)
+                .values(Invitation::new(
+                    email,
+                    Role::User,
+                    token.clone(),
+                    invited_by,
+                    expires_at,
+                ))
+                .execute(conn)
+                .expect("Can not insert");
+            assert!(select_pending(conn).expect("Can not select").is_empty());
+            let result = accept(&token, "Invitee".to_string(), "password", conn);
+            assert_eq!(result, Err(Error::Unauthorized));
+        })
+        .await;
+    }
+}
@@ -0,0 +1,205 @@
+use crate::config::{login_backoff_seconds, login_lockout_minutes, login_lockout_threshold};
+use crate::error::Error;
+use crate::models::{enums::LoginSubject, LoginAttempt};
+use crate::resources::validation::users::normalize_email;
+use crate::schema::login_attempts;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    QueryDsl, RunQueryDsl,
+};
+
+/// Fails while the email or the client address is locked out, or has not yet waited out the
+/// backoff that doubles with every failed attempt.
+pub fn check(email: &str, ip: Option<&str>, conn: &mut PgConnection) -> Result<(), Error> {
+    let now = Utc::now().naive_utc();
+    let mut query = login_attempts::dsl::login_attempts
+        .filter(
+            login_attempts::dsl::subject
+                .eq(LoginSubject::Email)
+                .and(login_attempts::dsl::identifier.eq(normalize(email))),
+        )
+        .into_boxed();
+    if let Some(ip) = ip {
+        query = query.or_filter(
+            login_attempts::dsl::subject
+                .eq(LoginSubject::Ip)
+                .and(login_attempts::dsl::identifier.eq(ip.to_string())),
+        );
+    }
+    let attempts = query.load::<LoginAttempt>(conn).map_err(Error::from)?;
+    if attempts.iter().any(|attempt| retry_at(attempt) > now) {
+        return Err(Error::TooManyAttempts);
+    }
+    Ok(())
+}
+
+/// The failures counted for an email, the ones of client addresses are not attributed to users.
+pub fn select_by_email(
+    email: &str,
+    conn: &mut PgConnection,
+) -> Result<Option<LoginAttempt>, Error> {
+    login_attempts::dsl::login_attempts
+        .find((LoginSubject::Email, normalize(email)))
+        .first(conn)
+        .optional()
+        .map_err(Error::from)
+}
+
+pub fn record_failure(email: &str, ip: Option<&str>, conn: &mut PgConnection) -> Result<(), Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        increment(LoginSubject::Email, &normalize(email), conn)?;
+        if let Some(ip) = ip {
+            increment(LoginSubject::Ip, ip, conn)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn clear(
+    email: Option<&str>,
+    ip: Option<&str>,
+    conn: &mut PgConnection,
+) -> Result<usize, Error> {
+    let mut count = 0;
+    if let Some(email) = email {
+        count += delete(LoginSubject::Email, &normalize(email), conn)?;
+    }
+    if let Some(ip) = ip {
+        count += delete(LoginSubject::Ip, ip, conn)?;
+    }
+    Ok(count)
+}
+
+fn delete(
+    subject: LoginSubject,
+    identifier: &str,
+    conn: &mut PgConnection,
+) -> Result<usize, Error> {
+    diesel::delete(login_attempts::dsl::login_attempts.find((subject, identifier)))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+/// Counting starts over once a lockout ran out, or when the last failure is older than a lockout.
+fn increment(
+    subject: LoginSubject,
+    identifier: &str,
+    conn: &mut PgConnection,
+) -> Result<(), Error> {
+    let now = Utc::now().naive_utc();
+    let lockout = Duration::minutes(login_lockout_minutes());
+    let previous = login_attempts::dsl::login_attempts
+        .find((subject, identifier))
+        .for_update()
+        .first::<LoginAttempt>(conn)
+        .optional()?;
+    let failures = match previous {
+        Some(attempt)
+            if attempt
+                .locked_until()
+                .unwrap_or(*attempt.last_failed_at() + lockout)
+                > now =>
+        {
+            attempt.failures() + 1
+        }
+        _ => 1,
+    };
+    let locked_until = (failures >= login_lockout_threshold()).then(|| now + lockout);
+    let attempt = LoginAttempt::new(subject, identifier.to_string(), failures, locked_until);
+    diesel::insert_into(login_attempts::dsl::login_attempts)
+        .values(&attempt)
+        .on_conflict((
+            login_attempts::dsl::subject,
+            login_attempts::dsl::identifier,
+        ))
+        .do_update()
+        .set((
+            login_attempts::dsl::failures.eq(failures),
+            login_attempts::dsl::last_failed_at.eq(now),
+            login_attempts::dsl::locked_until.eq(locked_until),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn retry_at(attempt: &LoginAttempt) -> NaiveDateTime {
+    if let Some(locked_until) = attempt.locked_until() {
+        return *locked_until;
+    }
+    let exponent = (attempt.failures() - 1).clamp(0, 20) as u32;
+    let backoff = Duration::seconds(login_backoff_seconds().saturating_mul(2_i64.pow(exponent)))
+        .min(Duration::minutes(login_lockout_minutes()));
+    *attempt.last_failed_at() + backoff
+}
+
+/// Compared like the emails of users, so every spelling of an address shares one lockout.
+fn normalize(email: &str) -> String {
+    normalize_email(email).to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+
+    fn rewind(conn: &mut PgConnection) {
+        diesel::update(login_attempts::dsl::login_attempts)
+            .set(
+                login_attempts::dsl::last_failed_at.eq(Utc::now().naive_utc() - Duration::hours(1)),
+            )
+            .execute(conn)
+            .expect("Can not update");
+    }
+
+    #[test]
+    fn emails_are_normalized_like_users() {
+        assert_eq!(
+            normalize(" Cafe\u{301}@Example.com"),
+            normalize("café@example.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn failure_backs_off() {
+        run_migrations_and_test_transactions(|conn| {
+            check("foo@bar.com", None, conn).expect("Can not check");
+            record_failure("foo@bar.com", None, conn).expect("Can not record");
+            assert_eq!(
+                check(" FOO@bar.com", None, conn),
+                Err(Error::TooManyAttempts)
+            );
+            rewind(conn);
+            check("foo@bar.com", None, conn).expect("Can not check");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn threshold_locks_out_until_cleared() {
+        run_migrations_and_test_transactions(|conn| {
+            for _ in 0..login_lockout_threshold() {
+                record_failure("foo@bar.com", Some("10.0.0.1"), conn).expect("Can not record");
+            }
+            rewind(conn);
+            assert_eq!(
+                check("foo@bar.com", None, conn),
+                Err(Error::TooManyAttempts)
+            );
+            assert_eq!(
+                check("baz@bar.com", Some("10.0.0.1"), conn),
+                Err(Error::TooManyAttempts)
+            );
+            clear(Some("foo@bar.com"), None, conn).expect("Can not clear");
+            check("foo@bar.com", None, conn).expect("Can not check");
+            assert_eq!(
+                check("foo@bar.com", Some("10.0.0.1"), conn),
+                Err(Error::TooManyAttempts)
+            );
+            clear(None, Some("10.0.0.1"), conn).expect("Can not clear");
+            check("foo@bar.com", Some("10.0.0.1"), conn).expect("Can not check");
+        })
+        .await;
+    }
+}
@@ -0,0 +1,63 @@
+use crate::error::Error;
+use crate::models::LoginChallenge;
+use crate::schema::login_challenges;
+
+use chrono::{Duration, Utc};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl,
+    RunQueryDsl,
+};
+use uuid::Uuid;
+
+/// Minutes between a correct password and the second factor.
+const TTL_MINUTES: i64 = 5;
+
+pub fn insert(user_id: Uuid, token_hash: String, conn: &mut PgConnection) -> Result<Uuid, Error> {
+    prune(conn)?;
+    let expires_at = Utc::now().naive_utc() + Duration::minutes(TTL_MINUTES);
+    let challenge = LoginChallenge::new(user_id, token_hash, expires_at);
+    diesel::insert_into(login_challenges::dsl::login_challenges)
+        .values(challenge)
+        .returning(login_challenges::dsl::id)
+        .get_result(conn)
+        .map_err(Error::from)
+}
+
+/// Challenges of abandoned sign ins are never used up.
+fn prune(conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::delete(login_challenges::dsl::login_challenges)
+        .filter(login_challenges::dsl::expires_at.lt(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+/// The user of an unused and unexpired challenge. A wrong code does not use the challenge up, so
+/// typos can be retried while the login throttling applies.
+pub fn select_user(token_hash: &str, conn: &mut PgConnection) -> Result<Uuid, Error> {
+    login_challenges::dsl::login_challenges
+        .filter(
+            login_challenges::dsl::token_hash
+                .eq(token_hash)
+                .and(login_challenges::dsl::used_at.is_null())
+                .and(login_challenges::dsl::expires_at.gt(Utc::now().naive_utc())),
+        )
+        .select(login_challenges::dsl::user_id)
+        .first::<Uuid>(conn)
+        .optional()?
+        .ok_or(Error::Unauthorized)
+}
+
+pub fn consume(token_hash: &str, conn: &mut PgConnection) -> Result<(), Error> {
+    let count = diesel::update(login_challenges::dsl::login_challenges)
+        .filter(
+            login_challenges::dsl::token_hash
+                .eq(token_hash)
+                .and(login_challenges::dsl::used_at.is_null()),
+        )
+        .set(login_challenges::dsl::used_at.eq(Utc::now().naive_utc()))
+        .execute(conn)?;
+    match count {
+        0 => Err(Error::Unauthorized),
+        _ => Ok(()),
+    }
+}
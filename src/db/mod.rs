@@ -1,5 +1,16 @@
+pub mod access_tokens;
+pub mod api_keys;
 pub mod authors;
+pub mod exports;
+pub mod invitations;
+pub mod login_attempts;
+pub mod login_challenges;
 pub mod migrations;
+pub mod oidc;
+pub mod password_resets;
+pub mod refresh_tokens;
+pub mod sessions;
 pub mod snippets;
 pub mod terms;
+pub mod two_factor;
 pub mod users;
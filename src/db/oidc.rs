@@ -0,0 +1,152 @@
+use crate::db::users;
+use crate::error::Error;
+use crate::models::{enums::Role, OidcLogin, User, UserIdentity};
+use crate::oidc::IdClaims;
+use crate::resources::validation::users::normalize_email;
+use crate::schema::{oidc_logins, user_identities, users as users_table};
+
+use chrono::{Duration, Utc};
+use diesel::{
+    Connection, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl,
+};
+use uuid::Uuid;
+
+/// Minutes the provider has to redirect back after the sign in started.
+const TTL_MINUTES: i64 = 10;
+
+pub fn insert_login(
+    state_hash: String,
+    nonce: String,
+    code_verifier: String,
+    conn: &mut PgConnection,
+) -> Result<Uuid, Error> {
+    prune(conn)?;
+    let expires_at = Utc::now().naive_utc() + Duration::minutes(TTL_MINUTES);
+    let login = OidcLogin::new(state_hash, nonce, code_verifier, expires_at);
+    diesel::insert_into(oidc_logins::dsl::oidc_logins)
+        .values(login)
+        .returning(oidc_logins::dsl::id)
+        .get_result(conn)
+        .map_err(Error::from)
+}
+
+/// Sign ins abandoned at the provider never come back to be taken.
+fn prune(conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::delete(oidc_logins::dsl::oidc_logins)
+        .filter(oidc_logins::dsl::expires_at.lt(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+/// Removes the started sign in of `state`, so it can only complete once.
+pub fn take_login(state_hash: &str, conn: &mut PgConnection) -> Result<OidcLogin, Error> {
+    let login = diesel::delete(oidc_logins::dsl::oidc_logins)
+        .filter(oidc_logins::dsl::state_hash.eq(state_hash))
+        .get_result::<OidcLogin>(conn)
+        .optional()?
+        .ok_or(Error::Unauthorized)?;
+    if *login.expires_at() < Utc::now().naive_utc() {
+        return Err(Error::Unauthorized);
+    }
+    Ok(login)
+}
+
+/// Finds the user linked to the subject, or links the user with the verified email. Without
+/// either, an account is created with `provision_role` when it is set.
+pub fn sign_in(
+    claims: &IdClaims,
+    provision_role: Option<Role>,
+    conn: &mut PgConnection,
+) -> Result<(Uuid, Role), Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        let linked = user_identities::dsl::user_identities
+            .find((&claims.iss, &claims.sub))
+            .inner_join(users_table::table)
+            .filter(users_table::dsl::is_deleted.eq(false))
+            .select((users_table::dsl::id, users_table::dsl::role))
+            .first::<(Uuid, Role)>(conn)
+            .optional()?;
+        if let Some(user) = linked {
+            return Ok(user);
+        }
+        let email = match (&claims.email, claims.email_verified) {
+            (Some(email), Some(true)) => email,
+            _ => return Err(Error::Forbidden),
+        };
+        let existing = users::select_by_email(email, conn)?.map(|user| (*user.id(), *user.role()));
+        let (user_id, role) = match (existing, provision_role) {
+            (Some(user), _) => user,
+            (None, Some(role)) => {
+                let email = normalize_email(email);
+                let name = claims.name.clone().unwrap_or_else(|| email.clone());
+                let user_id = diesel::insert_into(users_table::dsl::users)
+                    .values(User::new(name, email, role))
+                    .returning(users_table::dsl::id)
+                    .get_result::<Uuid>(conn)?;
+                (user_id, role)
+            }
+            (None, None) => return Err(Error::Forbidden),
+        };
+        let identity = UserIdentity::new(claims.iss.clone(), claims.sub.clone(), user_id);
+        diesel::insert_into(user_identities::dsl::user_identities)
+            .values(identity)
+            .execute(conn)?;
+        Ok((user_id, role))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::models::Password;
+    use fake::{Fake, Faker};
+
+    fn claims(sub: &str, email: &str, email_verified: bool) -> IdClaims {
+        serde_json::from_value(serde_json::json!({
+            "iss": "https://issuer.example.com",
+            "sub": sub,
+            "email": email,
+            "email_verified": email_verified,
+        }))
+        .expect("Can not decode claims")
+    }
+
+    #[tokio::test]
+    async fn subject_is_linked_by_verified_email() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::Editor);
+            let email = user.email().clone();
+            let password: Password = Faker.fake();
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+
+            let result = sign_in(&claims("subject", &email, false), None, conn);
+            assert_eq!(result, Err(Error::Forbidden));
+
+            let signed_in = sign_in(&claims("subject", &email, true), None, conn);
+            assert_eq!(signed_in, Ok((user_id, Role::Editor)));
+
+            let other_email = User::fake(Role::User).email().clone();
+            let signed_in = sign_in(&claims("subject", &other_email, true), None, conn);
+            assert_eq!(signed_in, Ok((user_id, Role::Editor)));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn unknown_users_are_provisioned_when_configured() {
+        run_migrations_and_test_transactions(|conn| {
+            let email = User::fake(Role::User).email().clone();
+            let result = sign_in(&claims("new subject", &email, true), None, conn);
+            assert_eq!(result, Err(Error::Forbidden));
+
+            let (user_id, role) =
+                sign_in(&claims("new subject", &email, true), Some(Role::User), conn)
+                    .expect("Can not provision");
+            assert_eq!(role, Role::User);
+            let user = users::select(user_id, conn).expect("Can not select");
+            assert_eq!(user.email(), &email);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,110 @@
+use crate::config::password_reset_ttl_minutes;
+use crate::db::users;
+use crate::error::Error;
+use crate::models::PasswordReset;
+use crate::schema::password_resets;
+
+use chrono::{Duration, Utc};
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    QueryDsl, RunQueryDsl,
+};
+use uuid::Uuid;
+
+pub fn insert(user_id: Uuid, token_hash: String, conn: &mut PgConnection) -> Result<Uuid, Error> {
+    prune(conn)?;
+    let expires_at = Utc::now().naive_utc() + Duration::minutes(password_reset_ttl_minutes());
+    let reset = PasswordReset::new(user_id, token_hash, expires_at);
+    diesel::insert_into(password_resets::dsl::password_resets)
+        .values(reset)
+        .returning(password_resets::dsl::id)
+        .get_result(conn)
+        .map_err(Error::from)
+}
+
+/// Expired links can not be redeemed anymore.
+fn prune(conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::delete(password_resets::dsl::password_resets)
+        .filter(password_resets::dsl::expires_at.lt(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+/// Marks the pending resets of a user as used, so their links stop working.
+pub fn revoke_pending(user_id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::update(password_resets::dsl::password_resets)
+        .filter(
+            password_resets::dsl::user_id
+                .eq(user_id)
+                .and(password_resets::dsl::used_at.is_null()),
+        )
+        .set(password_resets::dsl::used_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+/// Sets a new password for the owner of an unused and unexpired reset token. Every other pending
+/// reset of the same user is consumed along with it.
+pub fn redeem(token_hash: &str, password: &str, conn: &mut PgConnection) -> Result<(), Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        let reset = password_resets::dsl::password_resets
+            .filter(password_resets::dsl::token_hash.eq(token_hash))
+            .for_update()
+            .first::<PasswordReset>(conn)
+            .optional()?
+            .ok_or(Error::Unauthorized)?;
+        let now = Utc::now().naive_utc();
+        if reset.used_at().is_some() || *reset.expires_at() < now {
+            return Err(Error::Unauthorized);
+        }
+        revoke_pending(*reset.user_id(), conn)?;
+        users::reset_password(*reset.user_id(), password, conn)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{generate_token, hash_token};
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::models::{enums::Role, Password, User};
+    use fake::{Fake, Faker};
+
+    #[tokio::test]
+    async fn redeem_is_single_use() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let email = user.email().clone();
+            let password: Password = Faker.fake();
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+            let token = hash_token(&generate_token());
+            insert(user_id, token.clone(), conn).expect("Can not insert");
+
+            redeem(&token, "new password", conn).expect("Can not redeem");
+            users::authenticate(&email, "new password", conn).expect("Can not authenticate");
+
+            let result = redeem(&token, "other password", conn);
+            assert_eq!(result, Err(Error::Unauthorized));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_rejected() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let password: Password = Faker.fake();
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+            let token = hash_token(&generate_token());
+            let expires_at = Utc::now().naive_utc() - Duration::minutes(1);
+            diesel::insert_into(password_resets::dsl::password_resets)
+                .values(PasswordReset::new(user_id, token.clone(), expires_at))
+                .execute(conn)
+                .expect("Can not insert");
+
+            let result = redeem(&token, "new password", conn);
+            assert_eq!(result, Err(Error::Unauthorized));
+        })
+        .await;
+    }
+}
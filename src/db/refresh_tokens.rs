@@ -0,0 +1,221 @@
+use crate::config::refresh_token_ttl_days;
+use crate::error::Error;
+use crate::models::{enums::Role, RefreshToken};
+use crate::schema::{refresh_tokens, users};
+
+use chrono::{Duration, Utc};
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    QueryDsl, RunQueryDsl,
+};
+use uuid::Uuid;
+
+pub fn insert(
+    user_id: Uuid,
+    family_id: Uuid,
+    token_hash: String,
+    conn: &mut PgConnection,
+) -> Result<Uuid, Error> {
+    prune(conn)?;
+    let expires_at = Utc::now().naive_utc() + Duration::days(refresh_token_ttl_days());
+    let token = RefreshToken::new(user_id, family_id, token_hash, expires_at);
+    diesel::insert_into(refresh_tokens::dsl::refresh_tokens)
+        .values(token)
+        .returning(refresh_tokens::dsl::id)
+        .get_result(conn)
+        .map_err(Error::from)
+}
+
+/// Copies of expired tokens can not be reused, so they no longer need to be remembered.
+fn prune(conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::delete(refresh_tokens::dsl::refresh_tokens)
+        .filter(refresh_tokens::dsl::expires_at.lt(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+/// Exchanges a refresh token for a new one of the same family. Presenting a token that was
+/// already used revokes the whole family, since either the client or an attacker holds a copy.
+pub fn rotate(
+    token_hash: &str,
+    new_token_hash: String,
+    conn: &mut PgConnection,
+) -> Result<(Uuid, Role, Uuid), Error> {
+    let rotated = conn.transaction::<_, Error, _>(|conn| {
+        let token = refresh_tokens::dsl::refresh_tokens
+            .filter(refresh_tokens::dsl::token_hash.eq(token_hash))
+            .for_update()
+            .first::<RefreshToken>(conn)
+            .optional()?
+            .ok_or(Error::Unauthorized)?;
+        if token.revoked_at().is_some() {
+            return Err(Error::Unauthorized);
+        }
+        if token.used_at().is_some() {
+            revoke_family(*token.family_id(), conn)?;
+            return Ok(None);
+        }
+        let now = Utc::now().naive_utc();
+        if *token.expires_at() < now {
+            return Err(Error::Unauthorized);
+        }
+        let role = users::dsl::users
+            .filter(
+                users::dsl::is_deleted
+                    .eq(false)
+                    .and(users::dsl::id.eq(token.user_id())),
+            )
+            .select(users::dsl::role)
+            .first::<Role>(conn)
+            .optional()?
+            .ok_or(Error::Unauthorized)?;
+        diesel::update(refresh_tokens::dsl::refresh_tokens.find(token.id()))
+            .set(refresh_tokens::dsl::used_at.eq(now))
+            .execute(conn)?;
+        insert(*token.user_id(), *token.family_id(), new_token_hash, conn)?;
+        Ok(Some((*token.user_id(), role, *token.family_id())))
+    })?;
+    rotated.ok_or(Error::Unauthorized)
+}
+
+pub fn revoke_family(family_id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::update(refresh_tokens::dsl::refresh_tokens)
+        .filter(
+            refresh_tokens::dsl::family_id
+                .eq(family_id)
+                .and(refresh_tokens::dsl::revoked_at.is_null()),
+        )
+        .set(refresh_tokens::dsl::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+pub fn revoke_all(user_id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::update(refresh_tokens::dsl::refresh_tokens)
+        .filter(
+            refresh_tokens::dsl::user_id
+                .eq(user_id)
+                .and(refresh_tokens::dsl::revoked_at.is_null()),
+        )
+        .set(refresh_tokens::dsl::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+pub fn revoke_others(
+    user_id: Uuid,
+    family_id: Uuid,
+    conn: &mut PgConnection,
+) -> Result<usize, Error> {
+    diesel::update(refresh_tokens::dsl::refresh_tokens)
+        .filter(
+            refresh_tokens::dsl::user_id
+                .eq(user_id)
+                .and(refresh_tokens::dsl::family_id.ne(family_id))
+                .and(refresh_tokens::dsl::revoked_at.is_null()),
+        )
+        .set(refresh_tokens::dsl::revoked_at.eq(Utc::now().naive_utc()))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{generate_token, hash_token};
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::{access_tokens, users};
+    use crate::models::{Password, User};
+    use fake::{Fake, Faker};
+
+    fn create_user(conn: &mut PgConnection) -> Uuid {
+        let user = User::fake(Role::User);
+        let password: Password = Faker.fake();
+        users::insert(user, password.password(), conn).expect("Can not create")
+    }
+
+    #[tokio::test]
+    async fn rotation_issues_new_token() {
+        run_migrations_and_test_transactions(|conn| {
+            let user_id = create_user(conn);
+            let family_id = Uuid::new_v4();
+            let first = hash_token(&generate_token());
+            insert(user_id, family_id, first.clone(), conn).expect("Can not insert");
+
+            let second = hash_token(&generate_token());
+            let (id, role, family) = rotate(&first, second.clone(), conn).expect("Can not rotate");
+            assert_eq!(id, user_id);
+            assert_eq!(role, Role::User);
+            assert_eq!(family, family_id);
+
+            let third = hash_token(&generate_token());
+            rotate(&second, third, conn).expect("Can not rotate");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reuse_revokes_family() {
+        run_migrations_and_test_transactions(|conn| {
+            let user_id = create_user(conn);
+            let first = hash_token(&generate_token());
+            insert(user_id, Uuid::new_v4(), first.clone(), conn).expect("Can not insert");
+
+            let second = hash_token(&generate_token());
+            rotate(&first, second.clone(), conn).expect("Can not rotate");
+
+            let result = rotate(&first, hash_token(&generate_token()), conn);
+            assert_eq!(result, Err(Error::Unauthorized));
+
+            let result = rotate(&second, hash_token(&generate_token()), conn);
+            assert_eq!(result, Err(Error::Unauthorized));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_pruned() {
+        run_migrations_and_test_transactions(|conn| {
+            let user_id = create_user(conn);
+            let family_id = Uuid::new_v4();
+            let expired = Utc::now().naive_utc() - Duration::minutes(1);
+            let jti =
+                access_tokens::insert(user_id, family_id, expired, conn).expect("Can not insert");
+            insert(user_id, family_id, hash_token(&generate_token()), conn)
+                .expect("Can not insert");
+            diesel::update(refresh_tokens::dsl::refresh_tokens)
+                .filter(refresh_tokens::dsl::family_id.eq(family_id))
+                .set(refresh_tokens::dsl::expires_at.eq(expired))
+                .execute(conn)
+                .expect("Can not expire");
+
+            let expires_at = Utc::now().naive_utc() + Duration::minutes(30);
+            access_tokens::insert(user_id, family_id, expires_at, conn).expect("Can not insert");
+            insert(user_id, family_id, hash_token(&generate_token()), conn)
+                .expect("Can not insert");
+            assert!(!access_tokens::is_active(jti, conn).expect("Can not check token"));
+            let remaining = refresh_tokens::dsl::refresh_tokens
+                .filter(refresh_tokens::dsl::family_id.eq(family_id))
+                .count()
+                .get_result::<i64>(conn)
+                .expect("Can not count");
+            assert_eq!(remaining, 1);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn deleted_user_can_not_rotate() {
+        run_migrations_and_test_transactions(|conn| {
+            let user_id = create_user(conn);
+            let first = hash_token(&generate_token());
+            insert(user_id, Uuid::new_v4(), first.clone(), conn).expect("Can not insert");
+
+            users::delete(user_id, conn).expect("Can not delete");
+
+            let result = rotate(&first, hash_token(&generate_token()), conn);
+            assert_eq!(result, Err(Error::Unauthorized));
+        })
+        .await;
+    }
+}
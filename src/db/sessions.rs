@@ -0,0 +1,111 @@
+use crate::db::{access_tokens, refresh_tokens};
+use crate::error::Error;
+use crate::models::Session;
+use crate::schema::{refresh_tokens as refresh_tokens_table, sessions};
+
+use chrono::Utc;
+use diesel::dsl::exists;
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl,
+};
+use uuid::Uuid;
+
+/// Records a sign in, the session shares its id with the family of refresh tokens it starts.
+pub fn insert(session: Session, conn: &mut PgConnection) -> Result<Uuid, Error> {
+    diesel::insert_into(sessions::dsl::sessions)
+        .values(session)
+        .returning(sessions::dsl::id)
+        .get_result(conn)
+        .map_err(Error::from)
+}
+
+/// Sessions that can still be refreshed, most recent sign in first.
+pub fn select_active(user_id: Uuid, conn: &mut PgConnection) -> Result<Vec<Session>, Error> {
+    let refreshable = refresh_tokens_table::dsl::refresh_tokens.filter(
+        refresh_tokens_table::dsl::family_id
+            .eq(sessions::dsl::id)
+            .and(refresh_tokens_table::dsl::used_at.is_null())
+            .and(refresh_tokens_table::dsl::revoked_at.is_null())
+            .and(refresh_tokens_table::dsl::expires_at.gt(Utc::now().naive_utc())),
+    );
+    sessions::dsl::sessions
+        .filter(sessions::dsl::user_id.eq(user_id))
+        .filter(exists(refreshable))
+        .order(sessions::dsl::created_at.desc())
+        .load(conn)
+        .map_err(Error::from)
+}
+
+/// Signs out a single session of the user, both its access and its refresh tokens.
+pub fn revoke(user_id: Uuid, id: Uuid, conn: &mut PgConnection) -> Result<(), Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        sessions::dsl::sessions
+            .find(id)
+            .filter(sessions::dsl::user_id.eq(user_id))
+            .first::<Session>(conn)?;
+        access_tokens::revoke_family(id, conn)?;
+        refresh_tokens::revoke_family(id, conn)?;
+        Ok(())
+    })
+}
+
+pub fn revoke_all(user_id: Uuid, conn: &mut PgConnection) -> Result<(), Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        access_tokens::revoke_all(user_id, conn)?;
+        refresh_tokens::revoke_all(user_id, conn)?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{generate_token, hash_token};
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::users;
+    use crate::models::{enums::Role, Password, User};
+    use fake::{Fake, Faker};
+
+    fn sign_in(user_id: Uuid, conn: &mut PgConnection) -> Uuid {
+        let id = Uuid::new_v4();
+        let session = Session::new(id, user_id, None, Some("Test".to_string()));
+        insert(session, conn).expect("Can not insert");
+        refresh_tokens::insert(user_id, id, hash_token(&generate_token()), conn)
+            .expect("Can not insert");
+        id
+    }
+
+    #[tokio::test]
+    async fn revoked_sessions_are_not_listed() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let password: Password = Faker.fake();
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+            let first = sign_in(user_id, conn);
+            let second = sign_in(user_id, conn);
+            let ids = |conn: &mut PgConnection| {
+                let mut ids = select_active(user_id, conn)
+                    .expect("Can not select")
+                    .into_iter()
+                    .map(|session| *session.id())
+                    .collect::<Vec<_>>();
+                ids.sort();
+                ids
+            };
+            let mut both = vec![first, second];
+            both.sort();
+            assert_eq!(ids(conn), both);
+
+            assert_eq!(
+                revoke(Uuid::new_v4(), first, conn),
+                Err(Error::BadRequest("Not found".to_string()))
+            );
+            revoke(user_id, first, conn).expect("Can not revoke");
+            assert_eq!(ids(conn), vec![second]);
+
+            revoke_all(user_id, conn).expect("Can not revoke");
+            assert_eq!(ids(conn), Vec::<Uuid>::new());
+        })
+        .await;
+    }
+}
@@ -1,23 +1,112 @@
+use crate::config::search_language;
 use crate::error::Error;
 use crate::models::{AuthorSnippet, Snippet, TermSnippet};
+use crate::schema::sql_types::Regconfig;
 use crate::schema::{authors, authors_snippets, snippets, terms, terms_snippets};
 
 use crate::models::enums::Media;
+use chrono::NaiveDateTime;
+use diesel::dsl::{sql, AsExprOf};
+use diesel::expression::{AsExpression, SqlLiteral, TypedExpressionType, UncheckedBind};
 use diesel::pg::sql_types::Record;
-use diesel::sql_types::Array;
+use diesel::pg::Pg;
+use diesel::sql_types::{Array, Bool, Float, Nullable, SqlType, Text};
 use diesel::{
-    Connection, ExpressionMethods, JoinOnDsl, PgConnection, QueryDsl, Queryable, RunQueryDsl,
+    define_sql_function, BoolExpressionMethods, Connection, ExpressionMethods, JoinOnDsl,
+    PgConnection, PgSortExpressionMethods, QueryDsl, Queryable, RunQueryDsl,
 };
 use uuid::Uuid;
 
+/// Matches are wrapped in `<mark>` tags.
+const HEADLINE_OPTIONS: &str = "'MaxFragments=2, StartSel=<mark>, StopSel=</mark>'";
+
+/// The text with HTML escaped, so the only markup in headlines are the tags around matches.
+const ESCAPED_TEXT: &str = r#"replace(replace(replace(replace(replace(snippets.text,
+    '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '"', '&quot;'), '''', '&#39;')"#;
+
 #[derive(Queryable, Debug)]
 pub struct SnippetWithRelated {
     pub id: Uuid,
     pub text: String,
     pub media: Media,
     pub link: Option<String>,
+    pub created_at: NaiveDateTime,
     pub terms: Vec<(Uuid, String)>,
     pub authors: Vec<(Uuid, String)>,
+    /// Fragments of the text around the matches of a full-text search.
+    pub headline: Option<String>,
+}
+
+define_sql_function! { fn char_length(x: Text) -> Integer; }
+
+/// What snippets are sorted by instead of the default order, ties go to the newest. Snippets
+/// are sorted by the first name of their authors, those without any come last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    CreatedAt,
+    UpdatedAt,
+    TextLength,
+    AuthorName,
+    Media,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sort {
+    pub key: SortKey,
+    pub ascending: bool,
+}
+
+/// Where a page of snippets starts. A keyset is the `created_at` and `id` of a snippet, unlike
+/// offsets pages next to it stay put when snippets are added.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Position {
+    Offset(i64),
+    After(NaiveDateTime, Uuid),
+    Before(NaiveDateTime, Uuid),
+}
+
+/// Snippets to find, every filter that is set has to match.
+#[derive(Default, Debug)]
+pub struct SnippetFilter {
+    pub term_ids: Vec<Uuid>,
+    /// Whether snippets need all of `term_ids`, rather than any of them.
+    pub all_terms: bool,
+    pub author_ids: Vec<Uuid>,
+    pub media: Vec<Media>,
+    pub created_after: Option<NaiveDateTime>,
+    /// Exclusive, snippets created at that time are left out.
+    pub created_before: Option<NaiveDateTime>,
+    /// Full-text search in the syntax of web search engines, e.g. `"a phrase" or word -excluded`.
+    pub q: Option<String>,
+}
+
+type Language = SqlLiteral<Regconfig, UncheckedBind<SqlLiteral<Regconfig>, AsExprOf<String, Text>>>;
+
+type WithQuery<ST, Q> = SqlLiteral<
+    ST,
+    UncheckedBind<SqlLiteral<ST, UncheckedBind<SqlLiteral<ST>, AsExprOf<String, Text>>>, Q>,
+>;
+
+/// The text search configuration of `SEARCH_LANGUAGE`.
+fn language() -> Language {
+    sql::<Regconfig>("CAST(")
+        .bind::<Text, _>(search_language())
+        .sql(" AS regconfig)")
+}
+
+/// SQL between `before` and `after` around the query `q`, which is in the syntax of web search
+/// engines, e.g. `"a phrase" or word -excluded`. Stemmed with the configured language.
+fn with_query<ST, QT, Q>(before: &str, q: Q, after: &str) -> WithQuery<ST, Q::Expression>
+where
+    ST: TypedExpressionType,
+    QT: SqlType + TypedExpressionType,
+    Q: AsExpression<QT>,
+{
+    sql::<ST>(&format!("{before}websearch_to_tsquery(CAST("))
+        .bind::<Text, _>(search_language())
+        .sql(" AS regconfig), ")
+        .bind::<QT, _>(q)
+        .sql(&format!("){after}"))
 }
 
 pub fn delete(id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
@@ -45,7 +134,7 @@ pub fn insert(
 ) -> Result<Uuid, Error> {
     conn.transaction::<_, Error, _>(|conn| {
         let id = diesel::insert_into(snippets::dsl::snippets)
-            .values(snippet)
+            .values((snippet, snippets::dsl::search_language.eq(language())))
             .returning(snippets::dsl::id)
             .get_result(conn)
             .map_err(Error::from)?;
@@ -84,31 +173,69 @@ pub fn insert(
     })
 }
 
-pub fn count(term_id: Option<Uuid>, page_size: i64, conn: &mut PgConnection) -> Result<i64, Error> {
-    let count: i64 = match term_id {
-        None => snippets::dsl::snippets
-            .count()
-            .get_result(conn)
-            .map_err(Error::from)?,
-        Some(id) => snippets::dsl::snippets
-            .left_outer_join(
-                terms_snippets::dsl::terms_snippets
-                    .on(terms_snippets::dsl::snippet_id.eq(snippets::dsl::id)),
-            )
-            .filter(terms_snippets::dsl::term_id.eq(id))
-            .count()
-            .get_result(conn)
-            .map_err(Error::from)?,
-    };
-    Ok(count / page_size + (count % page_size).signum())
+/// Finds the snippets matching the filter, shared by counting and searching so both agree. Terms
+/// and authors are matched by subqueries, joining them would leave out the others of a snippet.
+fn filtered(filter: &SnippetFilter) -> snippets::BoxedQuery<'static, Pg> {
+    let mut query = snippets::dsl::snippets.into_boxed();
+    if filter.all_terms {
+        for id in &filter.term_ids {
+            let tagged = terms_snippets::dsl::terms_snippets
+                .select(terms_snippets::dsl::snippet_id)
+                .filter(terms_snippets::dsl::term_id.eq(*id));
+            query = query.filter(snippets::dsl::id.eq_any(tagged));
+        }
+    } else if !filter.term_ids.is_empty() {
+        let tagged = terms_snippets::dsl::terms_snippets
+            .select(terms_snippets::dsl::snippet_id)
+            .filter(terms_snippets::dsl::term_id.eq_any(filter.term_ids.clone()));
+        query = query.filter(snippets::dsl::id.eq_any(tagged));
+    }
+    if !filter.author_ids.is_empty() {
+        let written = authors_snippets::dsl::authors_snippets
+            .select(authors_snippets::dsl::snippet_id)
+            .filter(authors_snippets::dsl::author_id.eq_any(filter.author_ids.clone()));
+        query = query.filter(snippets::dsl::id.eq_any(written));
+    }
+    if !filter.media.is_empty() {
+        query = query.filter(snippets::dsl::media.eq_any(filter.media.clone()));
+    }
+    if let Some(after) = filter.created_after {
+        query = query.filter(snippets::dsl::created_at.ge(after));
+    }
+    if let Some(before) = filter.created_before {
+        query = query.filter(snippets::dsl::created_at.lt(before));
+    }
+    if let Some(q) = &filter.q {
+        query = query.filter(with_query::<Bool, Text, _>(
+            "snippets.text_search @@ ",
+            q.clone(),
+            "",
+        ));
+    }
+    query
 }
 
+pub fn count(filter: &SnippetFilter, conn: &mut PgConnection) -> Result<i64, Error> {
+    filtered(filter)
+        .count()
+        .get_result(conn)
+        .map_err(Error::from)
+}
+
+/// The newest snippets come first. With a full-text search and an offset, the most relevant do.
+/// Keysets are only for the default order of newest first, with which `sort` is ignored.
 pub fn search(
-    term_id: Option<Uuid>,
+    filter: &SnippetFilter,
+    sort: Option<Sort>,
     limit: Option<i64>,
-    offset: Option<i64>,
+    position: Position,
     conn: &mut PgConnection,
 ) -> Result<Vec<SnippetWithRelated>, Error> {
+    let headline = with_query::<Nullable<Text>, Nullable<Text>, _>(
+        &format!("ts_headline(snippets.search_language, {ESCAPED_TEXT}, "),
+        filter.q.clone(),
+        &format!(", {HEADLINE_OPTIONS})"),
+    );
     let mut query = snippets::dsl::snippets
         .left_outer_join(terms_snippets::dsl::terms_snippets.on(terms_snippets::dsl::snippet_id.eq(snippets::dsl::id)))
         .left_outer_join(terms::dsl::terms.on(terms::dsl::id.eq(terms_snippets::dsl::term_id)))
@@ -119,23 +246,76 @@ pub fn search(
              snippets::dsl::text,
              snippets::dsl::media,
              snippets::dsl::link,
+             snippets::dsl::created_at,
              diesel::dsl::sql::<Array<Record<(diesel::sql_types::Uuid, diesel::sql_types::Text)>>>("coalesce(array_agg(distinct (terms.id, terms.name)) filter (where terms.id is not null), '{}')"),
              diesel::dsl::sql::<Array<Record<(diesel::sql_types::Uuid, diesel::sql_types::Text)>>>("coalesce(array_agg(distinct (authors.id, authors.name)) filter (where authors.id is not null), '{}')"),
+             headline,
             )
         )
+        .filter(snippets::dsl::id.eq_any(filtered(filter).select(snippets::dsl::id)))
         .group_by(snippets::dsl::id)
-        .order(snippets::dsl::created_at.desc())
         .into_boxed();
-    if let Some(id) = term_id {
-        query = query.filter(terms_snippets::dsl::term_id.eq(id));
-    }
+    let newest = (snippets::dsl::created_at.desc(), snippets::dsl::id.desc());
+    query = match (position, sort, &filter.q) {
+        (Position::Offset(n), Some(sort), _) => {
+            let author = diesel::dsl::min(authors::dsl::name);
+            let length = char_length(snippets::dsl::text);
+            let sorted = match (sort.key, sort.ascending) {
+                (SortKey::CreatedAt, true) => query.order(snippets::dsl::created_at.asc()),
+                (SortKey::CreatedAt, false) => query.order(snippets::dsl::created_at.desc()),
+                (SortKey::UpdatedAt, true) => query.order(snippets::dsl::updated_at.asc()),
+                (SortKey::UpdatedAt, false) => query.order(snippets::dsl::updated_at.desc()),
+                (SortKey::TextLength, true) => query.order(length.asc()),
+                (SortKey::TextLength, false) => query.order(length.desc()),
+                (SortKey::AuthorName, true) => query.order(author.asc().nulls_last()),
+                (SortKey::AuthorName, false) => query.order(author.desc().nulls_last()),
+                (SortKey::Media, true) => query.order(snippets::dsl::media.asc()),
+                (SortKey::Media, false) => query.order(snippets::dsl::media.desc()),
+            };
+            sorted.then_order_by(newest).offset(n)
+        }
+        (Position::Offset(n), None, Some(q)) => {
+            let rank =
+                with_query::<Float, Text, _>("ts_rank(snippets.text_search, ", q.clone(), ")");
+            query.order(rank.desc()).then_order_by(newest).offset(n)
+        }
+        (Position::Offset(n), None, None) => query.order(newest).offset(n),
+        (Position::After(created_at, id), _, _) => query
+            .filter(
+                snippets::dsl::created_at
+                    .lt(created_at)
+                    .or(snippets::dsl::created_at
+                        .eq(created_at)
+                        .and(snippets::dsl::id.lt(id))),
+            )
+            .order(newest),
+        (Position::Before(created_at, id), _, _) => query
+            .filter(
+                snippets::dsl::created_at
+                    .gt(created_at)
+                    .or(snippets::dsl::created_at
+                        .eq(created_at)
+                        .and(snippets::dsl::id.gt(id))),
+            )
+            .order((snippets::dsl::created_at.asc(), snippets::dsl::id.asc())),
+    };
     if let Some(n) = limit {
         query = query.limit(n);
     }
-    if let Some(n) = offset {
-        query = query.offset(n);
+    let mut result = query.load(conn).map_err(Error::from)?;
+    if let Position::Before(..) = position {
+        result.reverse();
     }
-    query.load(conn).map_err(Error::from)
+    Ok(result)
+}
+
+/// Stems the snippets again after `SEARCH_LANGUAGE` changed, their `updated_at` is kept.
+pub fn update_search_language(conn: &mut PgConnection) -> Result<usize, Error> {
+    diesel::update(snippets::dsl::snippets)
+        .filter(snippets::dsl::search_language.ne(language()))
+        .set(snippets::dsl::search_language.eq(language()))
+        .execute(conn)
+        .map_err(Error::from)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -155,6 +335,7 @@ pub fn update(
                 snippets::dsl::text.eq(text),
                 snippets::dsl::media.eq(media),
                 snippets::dsl::link.eq(link),
+                snippets::dsl::search_language.eq(language()),
             ))
             .execute(conn)
             .map_err(Error::from)?;
@@ -213,3 +394,246 @@ pub fn select_media_stats(conn: &mut PgConnection) -> Result<Vec<(Media, i64)>,
         .load(conn)
         .map_err(Error::from)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::{authors as authors_db, terms as terms_db};
+    use crate::models::Term;
+    use chrono::{Duration, Utc};
+
+    #[tokio::test]
+    async fn full_text_search_is_ranked_and_stemmed() {
+        run_migrations_and_test_transactions(|conn| {
+            let mut create = |text: &str| {
+                let snippet = Snippet::new(text.to_string(), Media::Book, None);
+                insert(snippet, Vec::new(), Vec::new(), Vec::new(), conn).expect("Can not create")
+            };
+            let once = create("The cat was zorbling all night long");
+            let often = create("It zorbles, it zorbled and it will zorble again");
+            create("Nothing to see here");
+
+            let filter = SnippetFilter {
+                q: Some("zorble".to_string()),
+                ..Default::default()
+            };
+            let found =
+                search(&filter, None, None, Position::Offset(0), conn).expect("Can not search");
+            let ids = found.iter().map(|snippet| snippet.id).collect::<Vec<_>>();
+            assert_eq!(ids, vec![often, once]);
+            let headline = found[1].headline.as_deref().expect("Can not find headline");
+            assert!(headline.contains("<mark>zorbling</mark>"));
+            assert_eq!(count(&filter, conn), Ok(2));
+
+            let filter = SnippetFilter {
+                q: Some("zorble -cat".to_string()),
+                ..Default::default()
+            };
+            let found =
+                search(&filter, None, None, Position::Offset(0), conn).expect("Can not search");
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].id, often);
+
+            let filter = SnippetFilter::default();
+            let all =
+                search(&filter, None, Some(1), Position::Offset(0), conn).expect("Can not search");
+            assert_eq!(all[0].headline, None);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn headline_escapes_text() {
+        run_migrations_and_test_transactions(|conn| {
+            let text = r#"<img src="x" onerror='zorble'> & zorble"#;
+            let snippet = Snippet::new(text.to_string(), Media::Book, None);
+            insert(snippet, Vec::new(), Vec::new(), Vec::new(), conn).expect("Can not create");
+            let filter = SnippetFilter {
+                q: Some("zorble".to_string()),
+                ..Default::default()
+            };
+            let found =
+                search(&filter, None, None, Position::Offset(0), conn).expect("Can not search");
+            let headline = found[0].headline.as_deref().expect("Can not find headline");
+            assert!(headline.contains("&#39;<mark>zorble</mark>&#39;&gt; &amp;"));
+            let unmarked = headline.replace("<mark>", "").replace("</mark>", "");
+            assert!(!unmarked.contains(['<', '>', '"', '\'']));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn new_search_language_keeps_updated_at() {
+        run_migrations_and_test_transactions(|conn| {
+            let snippet = Snippet::new("Restemmed".to_string(), Media::Book, None);
+            let id =
+                insert(snippet, Vec::new(), Vec::new(), Vec::new(), conn).expect("Can not create");
+            let updated_at = diesel::update(snippets::dsl::snippets.find(id))
+                .set((
+                    snippets::dsl::search_language.eq(sql::<Regconfig>("'simple'")),
+                    snippets::dsl::updated_at.eq(Utc::now().naive_utc() - Duration::days(1)),
+                ))
+                .returning(snippets::dsl::updated_at)
+                .get_result::<NaiveDateTime>(conn)
+                .expect("Can not update");
+
+            assert!(update_search_language(conn).expect("Can not update") >= 1);
+            let (language, restemmed_at) = snippets::dsl::snippets
+                .find(id)
+                .select((
+                    sql::<Text>("search_language::text"),
+                    snippets::dsl::updated_at,
+                ))
+                .first::<(String, NaiveDateTime)>(conn)
+                .expect("Can not select");
+            assert_eq!(language, search_language());
+            assert_eq!(restemmed_at, updated_at);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn filters_are_combined() {
+        run_migrations_and_test_transactions(|conn| {
+            let term = |name: &str, conn: &mut PgConnection| {
+                terms_db::insert(Term::new(name.to_string()), Vec::new(), conn)
+                    .expect("Can not create")
+            };
+            let (a, b) = (term("Filter A", conn), term("Filter B", conn));
+            let author = authors_db::insert(vec!["Filter author".to_string()], conn)
+                .expect("Can not create")[0];
+            let mut create = |media: Media, terms: Vec<Uuid>, authors: Vec<Uuid>| {
+                let snippet = Snippet::new("Filtered".to_string(), media, None);
+                insert(snippet, terms, authors, Vec::new(), conn).expect("Can not create")
+            };
+            let both = create(Media::Video, vec![a, b], vec![author]);
+            let only_a = create(Media::Video, vec![a], vec![author]);
+            create(Media::Book, vec![a, b], vec![author]);
+            create(Media::Video, vec![a, b], Vec::new());
+
+            let mut filter = SnippetFilter {
+                term_ids: vec![a, b],
+                author_ids: vec![author],
+                media: vec![Media::Video],
+                ..Default::default()
+            };
+            let ids = |filter: &SnippetFilter, conn: &mut PgConnection| {
+                let mut ids = search(filter, None, None, Position::Offset(0), conn)
+                    .expect("Can not search")
+                    .into_iter()
+                    .map(|snippet| snippet.id)
+                    .collect::<Vec<_>>();
+                ids.sort();
+                ids
+            };
+            let mut any = vec![both, only_a];
+            any.sort();
+            assert_eq!(ids(&filter, conn), any);
+            assert_eq!(count(&filter, conn), Ok(2));
+
+            filter.all_terms = true;
+            assert_eq!(ids(&filter, conn), vec![both]);
+            assert_eq!(count(&filter, conn), Ok(1));
+
+            filter.created_before = Some(Utc::now().naive_utc() - Duration::days(1));
+            assert_eq!(ids(&filter, conn), Vec::<Uuid>::new());
+            assert_eq!(count(&filter, conn), Ok(0));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn keyset_pages_stay_put() {
+        run_migrations_and_test_transactions(|conn| {
+            let term = terms_db::insert(Term::new("Keyset".to_string()), Vec::new(), conn)
+                .expect("Can not create");
+            let create = |text: &str, conn: &mut PgConnection| {
+                let snippet = Snippet::new(text.to_string(), Media::News, None);
+                insert(snippet, vec![term], Vec::new(), Vec::new(), conn).expect("Can not create")
+            };
+            let [first, second, third] =
+                ["First", "Second", "Third"].map(|text| create(text, conn));
+            let filter = SnippetFilter {
+                term_ids: vec![term],
+                ..Default::default()
+            };
+            let page = |position: Position, conn: &mut PgConnection| {
+                search(&filter, None, Some(2), position, conn)
+                    .expect("Can not search")
+                    .into_iter()
+                    .map(|snippet| (snippet.created_at, snippet.id))
+                    .collect::<Vec<_>>()
+            };
+            let newest = page(Position::Offset(0), conn);
+            assert_eq!(
+                newest.iter().map(|(_, id)| *id).collect::<Vec<_>>(),
+                vec![third, second]
+            );
+            let (created_at, id) = newest[1];
+
+            create("Fourth", conn);
+            let older = page(Position::After(created_at, id), conn);
+            assert_eq!(
+                older.iter().map(|(_, id)| *id).collect::<Vec<_>>(),
+                vec![first]
+            );
+
+            let (created_at, id) = older[0];
+            let newer = page(Position::Before(created_at, id), conn);
+            assert_eq!(
+                newer.iter().map(|(_, id)| *id).collect::<Vec<_>>(),
+                vec![third, second]
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn sorts_break_ties_by_newest() {
+        run_migrations_and_test_transactions(|conn| {
+            let term = terms_db::insert(Term::new("Sorted".to_string()), Vec::new(), conn)
+                .expect("Can not create");
+            let authors = authors_db::insert(vec!["Bea".to_string(), "Ada".to_string()], conn)
+                .expect("Can not create");
+            let create = |text: &str, authors: Vec<Uuid>, conn: &mut PgConnection| {
+                let snippet = Snippet::new(text.to_string(), Media::Book, None);
+                insert(snippet, vec![term], authors, Vec::new(), conn).expect("Can not create")
+            };
+            let long = create("Longest text", vec![authors[0]], conn);
+            let short = create("Short", vec![authors[1]], conn);
+            let tied = create("Tied!", Vec::new(), conn);
+            let filter = SnippetFilter {
+                term_ids: vec![term],
+                ..Default::default()
+            };
+            let ids = |key: SortKey, ascending: bool, conn: &mut PgConnection| {
+                let sort = Sort { key, ascending };
+                search(&filter, Some(sort), None, Position::Offset(0), conn)
+                    .expect("Can not search")
+                    .into_iter()
+                    .map(|snippet| snippet.id)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                ids(SortKey::TextLength, true, conn),
+                vec![tied, short, long]
+            );
+            assert_eq!(
+                ids(SortKey::TextLength, false, conn),
+                vec![long, tied, short]
+            );
+            assert_eq!(
+                ids(SortKey::AuthorName, true, conn),
+                vec![short, long, tied]
+            );
+            assert_eq!(
+                ids(SortKey::AuthorName, false, conn),
+                vec![long, short, tied]
+            );
+            assert_eq!(ids(SortKey::CreatedAt, true, conn), vec![long, short, tied]);
+            assert_eq!(count(&filter, conn), Ok(3));
+        })
+        .await;
+    }
+}
@@ -0,0 +1,183 @@
+use crate::authentication::hash_token;
+use crate::error::Error;
+use crate::models::{RecoveryCode, TotpSecret};
+use crate::schema::{recovery_codes, totp_secrets};
+use crate::totp;
+
+use chrono::Utc;
+use diesel::{
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    QueryDsl, RunQueryDsl,
+};
+use uuid::Uuid;
+
+const RECOVERY_CODES: usize = 10;
+
+pub fn select(user_id: Uuid, conn: &mut PgConnection) -> Result<Option<TotpSecret>, Error> {
+    totp_secrets::dsl::totp_secrets
+        .find(user_id)
+        .first(conn)
+        .optional()
+        .map_err(Error::from)
+}
+
+pub fn is_enabled(user_id: Uuid, conn: &mut PgConnection) -> Result<bool, Error> {
+    let secret = select(user_id, conn)?;
+    Ok(secret.is_some_and(|secret| secret.confirmed_at().is_some()))
+}
+
+/// Stores a new secret that only takes effect once a code generated from it is verified. Refuses
+/// while two-factor authentication is enabled, it has to be disabled first.
+pub fn enrol(user_id: Uuid, secret: &[u8], conn: &mut PgConnection) -> Result<(), Error> {
+    let encrypted = totp::encrypt(secret)?;
+    conn.transaction::<_, Error, _>(|conn| {
+        if is_enabled(user_id, conn)? {
+            return Err(Error::BadRequest("Already exists".to_string()));
+        }
+        diesel::delete(totp_secrets::dsl::totp_secrets.find(user_id)).execute(conn)?;
+        diesel::insert_into(totp_secrets::dsl::totp_secrets)
+            .values(TotpSecret::new(user_id, encrypted))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Accepts a current code, or once enabled an unused recovery code. A code is accepted only once,
+/// and the first accepted one confirms a pending enrolment.
+pub fn verify(user_id: Uuid, code: &str, conn: &mut PgConnection) -> Result<(), Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        let stored = totp_secrets::dsl::totp_secrets
+            .find(user_id)
+            .for_update()
+            .first::<TotpSecret>(conn)
+            .optional()?
+            .ok_or(Error::Unauthorized)?;
+        let secret = totp::decrypt(stored.secret())?;
+        let step = totp::verify(&secret, code, Utc::now().timestamp());
+        match step {
+            Some(step) if stored.last_used_step().is_none_or(|last| last < step) => {
+                diesel::update(totp_secrets::dsl::totp_secrets.find(user_id))
+                    .set((
+                        totp_secrets::dsl::last_used_step.eq(step),
+                        totp_secrets::dsl::confirmed_at
+                            .eq(stored.confirmed_at().unwrap_or(Utc::now().naive_utc())),
+                    ))
+                    .execute(conn)?;
+                Ok(())
+            }
+            Some(_) => Err(Error::Unauthorized),
+            None if stored.confirmed_at().is_some() => use_recovery_code(user_id, code, conn),
+            None => Err(Error::Unauthorized),
+        }
+    })
+}
+
+/// Replaces the recovery codes, only their hashes are stored.
+pub fn generate_recovery_codes(
+    user_id: Uuid,
+    conn: &mut PgConnection,
+) -> Result<Vec<String>, Error> {
+    let codes = (0..RECOVERY_CODES)
+        .map(|_| totp::recovery_code())
+        .collect::<Vec<_>>();
+    conn.transaction::<_, Error, _>(|conn| {
+        diesel::delete(recovery_codes::dsl::recovery_codes)
+            .filter(recovery_codes::dsl::user_id.eq(user_id))
+            .execute(conn)?;
+        let rows = codes
+            .iter()
+            .map(|code| RecoveryCode::new(user_id, hash_token(code)))
+            .collect::<Vec<_>>();
+        diesel::insert_into(recovery_codes::dsl::recovery_codes)
+            .values(rows)
+            .execute(conn)?;
+        Ok(())
+    })?;
+    Ok(codes)
+}
+
+pub fn disable(user_id: Uuid, conn: &mut PgConnection) -> Result<(), Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        diesel::delete(recovery_codes::dsl::recovery_codes)
+            .filter(recovery_codes::dsl::user_id.eq(user_id))
+            .execute(conn)?;
+        diesel::delete(totp_secrets::dsl::totp_secrets.find(user_id)).execute(conn)?;
+        Ok(())
+    })
+}
+
+fn use_recovery_code(user_id: Uuid, code: &str, conn: &mut PgConnection) -> Result<(), Error> {
+    let count = diesel::update(recovery_codes::dsl::recovery_codes)
+        .filter(
+            recovery_codes::dsl::user_id
+                .eq(user_id)
+                .and(recovery_codes::dsl::code_hash.eq(hash_token(&totp::normalize(code))))
+                .and(recovery_codes::dsl::used_at.is_null()),
+        )
+        .set(recovery_codes::dsl::used_at.eq(Utc::now().naive_utc()))
+        .execute(conn)?;
+    match count {
+        0 => Err(Error::Unauthorized),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::users;
+    use crate::models::{enums::Role, Password, User};
+    use fake::{Fake, Faker};
+
+    fn user(conn: &mut PgConnection) -> Uuid {
+        let user = User::fake(Role::Admin);
+        let password: Password = Faker.fake();
+        users::insert(user, password.password(), conn).expect("Can not create")
+    }
+
+    #[tokio::test]
+    async fn first_code_confirms_enrolment() {
+        run_migrations_and_test_transactions(|conn| {
+            let user_id = user(conn);
+            let secret = totp::generate_secret();
+            enrol(user_id, &secret, conn).expect("Can not enrol");
+            assert_eq!(is_enabled(user_id, conn), Ok(false));
+
+            let code = totp::code(&secret, Utc::now().timestamp());
+            verify(user_id, &code, conn).expect("Can not verify");
+            assert_eq!(is_enabled(user_id, conn), Ok(true));
+            assert_eq!(verify(user_id, &code, conn), Err(Error::Unauthorized));
+            assert_eq!(
+                enrol(user_id, &secret, conn),
+                Err(Error::BadRequest("Already exists".to_string()))
+            );
+
+            disable(user_id, conn).expect("Can not disable");
+            assert_eq!(
+                select(user_id, conn).map(|secret| secret.is_none()),
+                Ok(true)
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_are_single_use() {
+        run_migrations_and_test_transactions(|conn| {
+            let user_id = user(conn);
+            let secret = totp::generate_secret();
+            enrol(user_id, &secret, conn).expect("Can not enrol");
+            let codes = generate_recovery_codes(user_id, conn).expect("Can not generate");
+            assert_eq!(codes.len(), RECOVERY_CODES);
+            assert_eq!(verify(user_id, &codes[0], conn), Err(Error::Unauthorized));
+
+            let code = totp::code(&secret, Utc::now().timestamp());
+            verify(user_id, &code, conn).expect("Can not verify");
+            verify(user_id, &codes[0].to_lowercase(), conn).expect("Can not verify");
+            assert_eq!(verify(user_id, &codes[0], conn), Err(Error::Unauthorized));
+            verify(user_id, &codes[1], conn).expect("Can not verify");
+        })
+        .await;
+    }
+}
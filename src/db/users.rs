@@ -1,24 +1,83 @@
-use crate::authentication::{hash_password, verify_password};
+use crate::authentication::{hash_password, needs_rehash, verify_password};
 use crate::error::Error;
 use crate::models::{enums::Role, Password, User};
+use crate::resources::validation::users::normalize_email;
 use crate::schema::{passwords, users};
 
 use crate::config::{admin_email, admin_password};
+use crate::db::{access_tokens, login_attempts, password_resets, refresh_tokens};
+use chrono::Utc;
+use diesel::define_sql_function;
+use diesel::sql_types::Text;
 use diesel::{
-    BoolExpressionMethods, Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl,
+    BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    QueryDsl, RunQueryDsl,
 };
+use log::warn;
 use uuid::Uuid;
 
+define_sql_function! {
+    /// Emails are unique by `lower(email)`, so lookups compare the same way to use that index.
+    fn lower(x: Text) -> Text;
+}
+
+/// Makes sure an active admin exists. Without one, the account of `ADMIN_EMAIL` is created, or
+/// restored as an admin with `ADMIN_PASSWORD` when it was deleted or demoted.
 pub fn create_admin_account_if_not_present(conn: &mut PgConnection) -> Result<(), Error> {
-    let present = users::dsl::users
-        .filter(users::dsl::email.eq(admin_email()))
-        .first::<User>(conn)
-        .map_err(Error::from);
-    if present.is_ok() {
-        return Ok(());
+    conn.transaction::<_, Error, _>(|conn| {
+        if !lock_active_admins(conn)?.is_empty() {
+            return Ok(());
+        }
+        let email = normalize_email(&admin_email());
+        let existing = users::dsl::users
+            .filter(lower(users::dsl::email).eq(lower(&email)))
+            .order(users::dsl::is_deleted.asc())
+            .select(users::dsl::id)
+            .first::<Uuid>(conn)
+            .optional()?;
+        let Some(id) = existing else {
+            let user = User::new("Admin".to_string(), email, Role::Admin);
+            return insert(user, &admin_password(), conn).map(|_| ());
+        };
+        warn!("No admin left, restoring {email} as an admin");
+        diesel::update(users::dsl::users.find(id))
+            .set((
+                users::dsl::role.eq(Role::Admin),
+                users::dsl::is_deleted.eq(false),
+            ))
+            .execute(conn)?;
+        let password_hash = hash_password(&admin_password())?;
+        diesel::insert_into(passwords::dsl::passwords)
+            .values(Password::new(id, password_hash.clone()))
+            .on_conflict(passwords::dsl::user_id)
+            .do_update()
+            .set(passwords::dsl::password.eq(password_hash))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Locks the rows of the active admins until the end of the transaction, so concurrent changes
+/// can not remove the last one between checking and changing it.
+fn lock_active_admins(conn: &mut PgConnection) -> Result<Vec<Uuid>, Error> {
+    users::dsl::users
+        .filter(
+            users::dsl::is_deleted
+                .eq(false)
+                .and(users::dsl::role.eq(Role::Admin)),
+        )
+        .select(users::dsl::id)
+        .for_update()
+        .load(conn)
+        .map_err(Error::from)
+}
+
+fn ensure_other_admin(id: Uuid, conn: &mut PgConnection) -> Result<(), Error> {
+    let admins = lock_active_admins(conn)?;
+    match admins.as_slice() {
+        [admin] if *admin == id => Err(Error::LastAdmin),
+        _ => Ok(()),
     }
-    let user = User::new("Admin".to_string(), admin_email(), Role::Admin);
-    insert(user, &admin_password(), conn).map(|_| ())
 }
 
 pub fn authenticate(
@@ -30,7 +89,7 @@ pub fn authenticate(
         .filter(
             users::dsl::is_deleted
                 .eq(false)
-                .and(users::dsl::email.eq(email)),
+                .and(lower(users::dsl::email).eq(lower(normalize_email(email)))),
         )
         .select((users::dsl::id, users::dsl::role))
         .first::<(Uuid, Role)>(conn)
@@ -41,16 +100,122 @@ pub fn authenticate(
         .first::<String>(conn)
         .map_err(Error::from)?;
     verify_password(password, &password_hash)?;
+    if needs_rehash(&password_hash)? {
+        update_password(user_id, password, conn)?;
+    }
     Ok((user_id, role))
 }
 
+pub fn change_password(
+    id: Uuid,
+    current_password: &str,
+    new_password: &str,
+    family_id: Uuid,
+    conn: &mut PgConnection,
+) -> Result<(), Error> {
+    let user = select(id, conn)?;
+    check_password_throttled(id, user.email(), current_password, conn)?;
+    conn.transaction::<_, Error, _>(|conn| {
+        update_password(id, new_password, conn)?;
+        access_tokens::revoke_others(id, family_id, conn)?;
+        refresh_tokens::revoke_others(id, family_id, conn)?;
+        Ok(())
+    })
+}
+
+/// Confirms the identity of a signed in user before a sensitive change.
+pub fn check_password(id: Uuid, password: &str, conn: &mut PgConnection) -> Result<(), Error> {
+    let password_hash = users::dsl::users
+        .inner_join(passwords::dsl::passwords)
+        .filter(users::dsl::is_deleted.eq(false).and(users::dsl::id.eq(id)))
+        .select(passwords::dsl::password)
+        .first::<String>(conn)
+        .map_err(Error::from)?;
+    verify_password(password, &password_hash)
+}
+
+/// Throttled like a sign in with the email, as a stolen token could otherwise guess the password.
+fn check_password_throttled(
+    id: Uuid,
+    email: &str,
+    password: &str,
+    conn: &mut PgConnection,
+) -> Result<(), Error> {
+    login_attempts::check(email, None, conn)?;
+    match check_password(id, password, conn) {
+        Ok(()) => login_attempts::clear(Some(email), None, conn).map(|_| ()),
+        Err(Error::BadRequest(message)) => {
+            login_attempts::record_failure(email, None, conn)?;
+            Err(Error::BadRequest(message))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn reset_password(id: Uuid, password: &str, conn: &mut PgConnection) -> Result<(), Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        select(id, conn)?;
+        update_password(id, password, conn)?;
+        revoke_tokens(id, conn)
+    })
+}
+
+fn update_password(id: Uuid, password: &str, conn: &mut PgConnection) -> Result<usize, Error> {
+    let password_hash = hash_password(password)?;
+    diesel::update(passwords::dsl::passwords.find(id))
+        .set((
+            passwords::dsl::password.eq(password_hash),
+            passwords::dsl::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .map_err(Error::from)
+}
+
 pub fn delete(id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        ensure_other_admin(id, conn)?;
+        let count = diesel::update(users::dsl::users.find(id))
+            .set((users::dsl::is_deleted.eq(true),))
+            .execute(conn)
+            .map_err(Error::from)?;
+        revoke_tokens(id, conn)?;
+        Ok(count)
+    })
+}
+
+pub fn select_deleted(conn: &mut PgConnection) -> Result<Vec<User>, Error> {
+    users::dsl::users
+        .filter(users::dsl::is_deleted.eq(true))
+        .order(users::dsl::name)
+        .load(conn)
+        .map_err(Error::from)
+}
+
+/// Fails with "Already exists" when the email was taken by another account in the meantime.
+pub fn restore(id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
     diesel::update(users::dsl::users.find(id))
-        .set((users::dsl::is_deleted.eq(true),))
+        .filter(users::dsl::is_deleted.eq(true))
+        .set(users::dsl::is_deleted.eq(false))
         .execute(conn)
         .map_err(Error::from)
 }
 
+/// Removes a deleted user for good, together with the password and everything else that
+/// references the account.
+pub fn purge(id: Uuid, conn: &mut PgConnection) -> Result<usize, Error> {
+    conn.transaction::<_, Error, _>(|conn| {
+        users::dsl::users
+            .filter(users::dsl::is_deleted.eq(true))
+            .find(id)
+            .select(users::dsl::id)
+            .first::<Uuid>(conn)?;
+        diesel::delete(passwords::dsl::passwords.find(id)).execute(conn)?;
+        diesel::delete(users::dsl::users.find(id))
+            .execute(conn)
+            .map_err(Error::from)
+    })
+}
+
 pub fn insert(user: User, password: &str, conn: &mut PgConnection) -> Result<Uuid, Error> {
     conn.transaction::<_, Error, _>(|conn| {
         let uuid = diesel::insert_into(users::dsl::users)
@@ -76,6 +241,18 @@ pub fn select(id: Uuid, conn: &mut PgConnection) -> Result<User, Error> {
         .map_err(Error::from)
 }
 
+pub fn select_by_email(email: &str, conn: &mut PgConnection) -> Result<Option<User>, Error> {
+    users::dsl::users
+        .filter(
+            users::dsl::is_deleted
+                .eq(false)
+                .and(lower(users::dsl::email).eq(lower(normalize_email(email)))),
+        )
+        .first(conn)
+        .optional()
+        .map_err(Error::from)
+}
+
 pub fn select_all(conn: &mut PgConnection) -> Result<Vec<User>, Error> {
     users::dsl::users
         .filter(users::dsl::is_deleted.eq(false))
@@ -91,14 +268,61 @@ pub fn update(
     role: Role,
     conn: &mut PgConnection,
 ) -> Result<usize, Error> {
-    diesel::update(users::dsl::users.find(id))
-        .set((
-            users::dsl::name.eq(name),
-            users::dsl::email.eq(email),
-            users::dsl::role.eq(role),
-        ))
-        .execute(conn)
-        .map_err(Error::from)
+    conn.transaction::<_, Error, _>(|conn| {
+        if role != Role::Admin {
+            ensure_other_admin(id, conn)?;
+        }
+        let previous = users::dsl::users
+            .find(id)
+            .select(users::dsl::role)
+            .first::<Role>(conn)
+            .optional()
+            .map_err(Error::from)?;
+        let count = diesel::update(users::dsl::users.find(id))
+            .set((
+                users::dsl::name.eq(name),
+                users::dsl::email.eq(email),
+                users::dsl::role.eq(role),
+            ))
+            .execute(conn)
+            .map_err(Error::from)?;
+        if previous.is_some_and(|previous| previous != role) {
+            revoke_tokens(id, conn)?;
+        }
+        Ok(count)
+    })
+}
+
+/// Lets users change their own name and email, but not their role. Changing the email needs the
+/// current password, throttled like a sign in, and revokes the pending password resets.
+pub fn update_profile(
+    id: Uuid,
+    name: String,
+    email: String,
+    password: Option<&str>,
+    conn: &mut PgConnection,
+) -> Result<usize, Error> {
+    let user = select(id, conn)?;
+    let email_changed = user.email() != &email;
+    if email_changed {
+        let password = password.ok_or(Error::BadRequest("Password required".to_string()))?;
+        check_password_throttled(id, user.email(), password, conn)?;
+    }
+    conn.transaction::<_, Error, _>(|conn| {
+        if email_changed {
+            password_resets::revoke_pending(id, conn)?;
+        }
+        diesel::update(users::dsl::users.find(id))
+            .set((users::dsl::name.eq(name), users::dsl::email.eq(email)))
+            .execute(conn)
+            .map_err(Error::from)
+    })
+}
+
+fn revoke_tokens(id: Uuid, conn: &mut PgConnection) -> Result<(), Error> {
+    access_tokens::revoke_all(id, conn)?;
+    refresh_tokens::revoke_all(id, conn)?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -127,6 +351,50 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn deleted_admin_account_is_restored() {
+        run_migrations_and_test_transactions(|conn| {
+            create_admin_account_if_not_present(conn).expect("Can not create admin");
+            diesel::update(users::dsl::users)
+                .set(users::dsl::is_deleted.eq(true))
+                .execute(conn)
+                .expect("Can not delete");
+            create_admin_account_if_not_present(conn).expect("Can not restore admin");
+            let (_, role) = authenticate(&admin_email(), &admin_password(), conn)
+                .expect("Can not authenticate admin");
+            assert_eq!(role, Role::Admin);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn last_admin_can_not_be_removed() {
+        run_migrations_and_test_transactions(|conn| {
+            diesel::update(users::dsl::users)
+                .set(users::dsl::is_deleted.eq(true))
+                .execute(conn)
+                .expect("Can not delete");
+            let password: Password = Faker.fake();
+            let [first, second] = [(); 2].map(|_| {
+                let user = User::fake(Role::Admin);
+                insert(user, password.password(), conn).expect("Can not create")
+            });
+            let (_, name, email, _, _, _, _) =
+                select(first, conn).expect("Can not select").dissolve();
+
+            delete(second, conn).expect("Can not delete");
+            assert_eq!(delete(first, conn), Err(Error::LastAdmin));
+            assert_eq!(
+                update(first, name.clone(), email.clone(), Role::Editor, conn),
+                Err(Error::LastAdmin)
+            );
+            update(first, name, email, Role::Admin, conn).expect("Can not update");
+            assert_eq!(restore(second, conn), Ok(1));
+            delete(first, conn).expect("Can not delete");
+        })
+        .await;
+    }
+
     #[tokio::test]
     async fn authenticate_admin() {
         run_migrations_and_test_transactions(|conn| {
@@ -156,6 +424,61 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn legacy_hash_is_upgraded_on_login() {
+        run_migrations_and_test_transactions(|conn| {
+            use argon2::password_hash::SaltString;
+            use argon2::{Argon2, PasswordHasher};
+
+            let user = User::fake(Role::User);
+            let email = user.email().clone();
+            let password: Password = Faker.fake();
+            let id = insert(user, password.password(), conn).expect("Can not create");
+            let salt = SaltString::from_b64(
+                &crate::config::hasher_salt().expect("HASHER_SALT must be set"),
+            )
+            .expect("Can not parse salt");
+            let legacy = Argon2::default()
+                .hash_password(password.password().as_bytes(), &salt)
+                .expect("Can not hash")
+                .to_string();
+            diesel::update(passwords::dsl::passwords.find(id))
+                .set(passwords::dsl::password.eq(&legacy))
+                .execute(conn)
+                .expect("Can not update");
+
+            authenticate(&email, password.password(), conn).expect("Can not authenticate");
+
+            let stored = passwords::dsl::passwords
+                .find(id)
+                .select(passwords::dsl::password)
+                .first::<String>(conn)
+                .expect("Can not select");
+            assert_ne!(stored, legacy);
+            assert!(!needs_rehash(&stored).expect("Can not check hash"));
+            authenticate(&email, password.password(), conn).expect("Can not authenticate");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn editor_role_is_stored() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::Editor);
+            let email = user.email().clone();
+            let password: Password = Faker.fake();
+            let id = insert(user, password.password(), conn).expect("Can not create");
+            let (_, role) =
+                authenticate(&email, password.password(), conn).expect("Can not authenticate");
+            assert_eq!(role, Role::Editor);
+            assert_eq!(
+                *select(id, conn).expect("Can not select").role(),
+                Role::Editor
+            );
+        })
+        .await;
+    }
+
     #[tokio::test]
     async fn soft_deletion() {
         run_migrations_and_test_transactions(|conn| {
@@ -183,6 +506,198 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn deleted_email_can_be_reused() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let (_, name, email, _, _, _, _) = User::fake(Role::User).dissolve();
+            let same_email = User::new(name, user.email().clone(), Role::User);
+            let password: Password = Faker.fake();
+            let id = insert(user, password.password(), conn).expect("Can not create");
+            assert_eq!(
+                purge(id, conn),
+                Err(Error::BadRequest("Not found".to_string()))
+            );
+
+            delete(id, conn).expect("Can not delete");
+            let deleted = select_deleted(conn).expect("Can not select deleted");
+            assert!(deleted.iter().any(|user| *user.id() == id));
+
+            let other = insert(same_email, password.password(), conn).expect("Can not create");
+            // A savepoint keeps the failed update from aborting the transaction of the test
+            let result = conn.transaction(|conn| restore(id, conn));
+            assert_eq!(result, Err(Error::BadRequest("Already exists".to_string())));
+            update(other, "Other".to_string(), email, Role::User, conn).expect("Can not update");
+            assert_eq!(restore(id, conn), Ok(1));
+            select(id, conn).expect("Can not select");
+
+            delete(id, conn).expect("Can not delete");
+            assert_eq!(purge(id, conn), Ok(1));
+            let deleted = select_deleted(conn).expect("Can not select deleted");
+            assert!(!deleted.iter().any(|user| *user.id() == id));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn email_is_case_insensitive() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::new("Foo".to_string(), "Foo@Foo.com".to_string(), Role::User);
+            let password: Password = Faker.fake();
+            let id = insert(user, password.password(), conn).expect("Can not create");
+
+            let (found, _) = authenticate(" foo@FOO.com", password.password(), conn)
+                .expect("Can not authenticate");
+            assert_eq!(found, id);
+            let found = select_by_email("FOO@foo.com", conn).expect("Can not select");
+            assert_eq!(found.map(|user| *user.id()), Some(id));
+
+            let user = User::new("Bar".to_string(), "foo@foo.com".to_string(), Role::User);
+            let result = insert(user, password.password(), conn);
+            assert_eq!(result, Err(Error::BadRequest("Already exists".to_string())));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn deletion_revokes_tokens() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let password: Password = Faker.fake();
+            let id = insert(user, password.password(), conn).expect("Can not create");
+            let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(30);
+            let jti = access_tokens::insert(id, Uuid::new_v4(), expires_at, conn)
+                .expect("Can not insert token");
+            assert!(access_tokens::is_active(jti, conn).expect("Can not check token"));
+
+            delete(id, conn).expect("Can not delete");
+
+            assert!(!access_tokens::is_active(jti, conn).expect("Can not check token"));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn role_change_revokes_tokens() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::Admin);
+            let (_, name, email, _, _, _, _) = User::fake(Role::Admin).dissolve();
+            let password: Password = Faker.fake();
+            let id = insert(user, password.password(), conn).expect("Can not create");
+            let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(30);
+            let jti = access_tokens::insert(id, Uuid::new_v4(), expires_at, conn)
+                .expect("Can not insert token");
+
+            update(id, name.clone(), email.clone(), Role::Admin, conn).expect("Can not update");
+            assert!(access_tokens::is_active(jti, conn).expect("Can not check token"));
+
+            update(id, name, email, Role::User, conn).expect("Can not update");
+            assert!(!access_tokens::is_active(jti, conn).expect("Can not check token"));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn email_change_needs_password() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::Editor);
+            let email = user.email().clone();
+            let (_, name, other_email, _, _, _, _) = User::fake(Role::User).dissolve();
+            let password: Password = Faker.fake();
+            let id = insert(user, password.password(), conn).expect("Can not create");
+
+            update_profile(id, name.clone(), email.clone(), None, conn).expect("Can not update");
+            assert_eq!(select(id, conn).expect("Can not select").name(), &name);
+
+            let result = update_profile(id, name.clone(), other_email.clone(), None, conn);
+            assert_eq!(
+                result,
+                Err(Error::BadRequest("Password required".to_string()))
+            );
+            let result = update_profile(
+                id,
+                name.clone(),
+                other_email.clone(),
+                Some("wrong password"),
+                conn,
+            );
+            assert!(result.is_err());
+            password_resets::insert(id, "hash".to_string(), conn).expect("Can not create");
+            let result = update_profile(
+                id,
+                name.clone(),
+                other_email.clone(),
+                Some(password.password()),
+                conn,
+            );
+            assert_eq!(result, Err(Error::TooManyAttempts));
+            login_attempts::clear(Some(&email), None, conn).expect("Can not clear");
+
+            update_profile(
+                id,
+                name,
+                other_email.clone(),
+                Some(password.password()),
+                conn,
+            )
+            .expect("Can not update");
+            let user = select(id, conn).expect("Can not select");
+            assert_eq!(user.email(), &other_email);
+            assert_eq!(user.role(), &Role::Editor);
+            let reset = password_resets::redeem("hash", password.password(), conn);
+            assert_eq!(reset, Err(Error::Unauthorized));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn change_password_revokes_other_sessions() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let email = user.email().clone();
+            let password: Password = Faker.fake();
+            let id = insert(user, password.password(), conn).expect("Can not create");
+            let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(30);
+            let current = Uuid::new_v4();
+            let current_jti =
+                access_tokens::insert(id, current, expires_at, conn).expect("Can not insert");
+            let other_jti = access_tokens::insert(id, Uuid::new_v4(), expires_at, conn)
+                .expect("Can not insert");
+
+            let result = change_password(id, "wrong password", "new password", current, conn);
+            assert!(result.is_err());
+            let result = change_password(id, password.password(), "new password", current, conn);
+            assert_eq!(result, Err(Error::TooManyAttempts));
+            login_attempts::clear(Some(&email), None, conn).expect("Can not clear");
+
+            change_password(id, password.password(), "new password", current, conn)
+                .expect("Can not change password");
+            assert!(authenticate(&email, password.password(), conn).is_err());
+            authenticate(&email, "new password", conn).expect("Can not authenticate");
+            assert!(access_tokens::is_active(current_jti, conn).expect("Can not check token"));
+            assert!(!access_tokens::is_active(other_jti, conn).expect("Can not check token"));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reset_password_revokes_all_sessions() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let email = user.email().clone();
+            let password: Password = Faker.fake();
+            let id = insert(user, password.password(), conn).expect("Can not create");
+            let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(30);
+            let jti = access_tokens::insert(id, Uuid::new_v4(), expires_at, conn)
+                .expect("Can not insert");
+
+            reset_password(id, "new password", conn).expect("Can not reset password");
+            authenticate(&email, "new password", conn).expect("Can not authenticate");
+            assert!(!access_tokens::is_active(jti, conn).expect("Can not check token"));
+        })
+        .await;
+    }
+
     #[tokio::test]
     async fn username_is_unique() {
         run_migrations_and_test_transactions(|conn| {
@@ -20,6 +20,12 @@ pub enum Error {
     #[status(FORBIDDEN)]
     #[display("Forbidden")]
     Forbidden,
+    #[status(CONFLICT)]
+    #[display("The last admin can not be removed")]
+    LastAdmin,
+    #[status(TOO_MANY_REQUESTS)]
+    #[display("Too many login attempts")]
+    TooManyAttempts,
     #[status(INTERNAL_SERVER_ERROR)]
     #[display("Internal Server Error")]
     InternalServerError,
@@ -48,6 +54,13 @@ impl From<argon2::password_hash::Error> for Error {
     }
 }
 
+impl From<argon2::Error> for Error {
+    fn from(error: argon2::Error) -> Self {
+        error!("Argon: {error:?}");
+        InternalServerError
+    }
+}
+
 impl From<jsonwebtoken::errors::Error> for Error {
     fn from(error: jsonwebtoken::errors::Error) -> Self {
         error!("JWT: {error:?}");
@@ -83,6 +96,34 @@ impl From<MigrationError> for Error {
     }
 }
 
+impl From<lettre::address::AddressError> for Error {
+    fn from(error: lettre::address::AddressError) -> Self {
+        error!("Mail address: {error:?}");
+        BadRequest("Invalid email address".to_string())
+    }
+}
+
+impl From<lettre::error::Error> for Error {
+    fn from(error: lettre::error::Error) -> Self {
+        error!("Mail: {error:?}");
+        InternalServerError
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for Error {
+    fn from(error: lettre::transport::smtp::Error) -> Self {
+        error!("SMTP: {error:?}");
+        InternalServerError
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        error!("HTTP: {error:?}");
+        InternalServerError
+    }
+}
+
 impl From<Box<dyn StdError + Send + Sync>> for Error {
     fn from(error: Box<dyn StdError + Send + Sync>) -> Self {
         error!("Migrations: {error:?}");
@@ -0,0 +1,186 @@
+use crate::config::{jwt_signing_key, jwt_verification_keys};
+use crate::error::Error;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+    OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use log::{error, warn};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::iter;
+use std::sync::OnceLock;
+
+static KEYRING: OnceLock<Keyring> = OnceLock::new();
+
+/// Signs tokens with one Ed25519 key and verifies them against it and any retired keys, which
+/// are still accepted while tokens they signed have not expired.
+pub struct Keyring {
+    kid: String,
+    signing: EncodingKey,
+    verifying: HashMap<String, DecodingKey>,
+    jwks: JwkSet,
+}
+
+impl Keyring {
+    /// `pkcs8` is the DER encoded signing key, `retired` the raw public keys of previous ones.
+    pub fn new(pkcs8: &[u8], retired: Vec<Vec<u8>>) -> Result<Self, Error> {
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8).map_err(|e| {
+            error!("Ed25519: {e:?}");
+            Error::InternalServerError
+        })?;
+        let current = pair.public_key().as_ref().to_vec();
+        let keys = iter::once(current)
+            .chain(retired)
+            .map(|public_key| jwk(&public_key))
+            .collect::<Vec<_>>();
+        let mut verifying = HashMap::new();
+        for key in &keys {
+            let kid = key.common.key_id.clone().unwrap_or_default();
+            verifying.insert(kid, DecodingKey::from_jwk(key)?);
+        }
+        Ok(Self {
+            kid: keys[0].common.key_id.clone().unwrap_or_default(),
+            signing: EncodingKey::from_ed_der(pkcs8),
+            verifying,
+            jwks: JwkSet { keys },
+        })
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(self.kid.clone());
+        jsonwebtoken::encode(&header, claims, &self.signing).map_err(Error::from)
+    }
+
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+    ) -> Result<T, jsonwebtoken::errors::Error> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = header
+            .kid
+            .and_then(|kid| self.verifying.get(&kid))
+            .ok_or(ErrorKind::InvalidSignature)?;
+        let validation = Validation::new(Algorithm::EdDSA);
+        jsonwebtoken::decode::<T>(token, key, &validation).map(|data| data.claims)
+    }
+
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+}
+
+/// Built from the configuration on first use. Debug builds without a configured signing key
+/// generate a temporary one, so every restart invalidates the issued tokens.
+pub fn keyring() -> &'static Keyring {
+    KEYRING.get_or_init(|| {
+        let pkcs8 = match jwt_signing_key() {
+            Some(key) => STANDARD
+                .decode(key.trim())
+                .expect("JWT_SIGNING_KEY must be a base64 encoded PKCS#8 key"),
+            None if !cfg!(debug_assertions) => panic!("JWT_SIGNING_KEY must be set"),
+            None => {
+                warn!("JWT_SIGNING_KEY is not set, signing tokens with a temporary key");
+                Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .expect("Can not generate a signing key")
+                    .as_ref()
+                    .to_vec()
+            }
+        };
+        let retired = jwt_verification_keys()
+            .iter()
+            .map(|key| {
+                URL_SAFE_NO_PAD
+                    .decode(key.trim())
+                    .expect("JWT_VERIFICATION_KEYS must be base64url encoded public keys")
+            })
+            .collect();
+        Keyring::new(&pkcs8, retired).expect("Invalid JWT keys")
+    })
+}
+
+/// The `kid` is the RFC 7638 thumbprint, so it stays the same across restarts and services.
+fn jwk(public_key: &[u8]) -> Jwk {
+    let x = URL_SAFE_NO_PAD.encode(public_key);
+    let thumbprint = Sha256::digest(format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{x}"}}"#));
+    Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(KeyAlgorithm::EdDSA),
+            key_id: Some(URL_SAFE_NO_PAD.encode(thumbprint)),
+            ..Default::default()
+        },
+        algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Claims {
+        sub: String,
+        exp: u64,
+    }
+
+    fn pkcs8() -> Vec<u8> {
+        Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .expect("Can not generate a key")
+            .as_ref()
+            .to_vec()
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "foo".to_string(),
+            exp: u64::MAX / 2,
+        }
+    }
+
+    fn public_key(keyring: &Keyring) -> Vec<u8> {
+        match &keyring.jwks().keys[0].algorithm {
+            AlgorithmParameters::OctetKeyPair(params) => {
+                URL_SAFE_NO_PAD.decode(&params.x).expect("Can not decode")
+            }
+            _ => panic!("Not an Ed25519 key"),
+        }
+    }
+
+    #[test]
+    fn signed_token_carries_kid() {
+        let keyring = Keyring::new(&pkcs8(), vec![]).expect("Can not build keyring");
+        let token = keyring.encode(&claims()).expect("Can not encode");
+        let header = jsonwebtoken::decode_header(&token).expect("Can not decode header");
+        assert_eq!(header.alg, Algorithm::EdDSA);
+        assert_eq!(header.kid, keyring.jwks().keys[0].common.key_id);
+        assert_eq!(keyring.decode::<Claims>(&token), Ok(claims()));
+    }
+
+    #[test]
+    fn retired_key_still_verifies() {
+        let previous = Keyring::new(&pkcs8(), vec![]).expect("Can not build keyring");
+        let token = previous.encode(&claims()).expect("Can not encode");
+        let current =
+            Keyring::new(&pkcs8(), vec![public_key(&previous)]).expect("Can not build keyring");
+        assert_eq!(current.jwks().keys.len(), 2);
+        assert_eq!(current.decode::<Claims>(&token), Ok(claims()));
+
+        let unrelated = Keyring::new(&pkcs8(), vec![]).expect("Can not build keyring");
+        assert!(unrelated.decode::<Claims>(&token).is_err());
+    }
+}
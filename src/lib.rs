@@ -12,7 +12,12 @@ pub mod authentication;
 pub mod config;
 pub mod db;
 pub mod error;
+pub mod jwt;
+pub mod mailer;
+pub mod middleware;
 pub mod models;
+pub mod oidc;
 pub mod resources;
 pub mod router;
 pub mod schema;
+pub mod totp;
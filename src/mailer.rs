@@ -0,0 +1,104 @@
+use crate::config::{mail_dir, mail_from, smtp_url};
+use crate::error::Error;
+
+use lettre::message::Mailbox;
+use lettre::{Message, SmtpTransport, Transport};
+use log::{error, info};
+use std::fs;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+pub trait Mailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), Error>;
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(url: &str, from: &str) -> Result<Self, Error> {
+        let transport = SmtpTransport::from_url(url)?.build();
+        let from = from.parse()?;
+        Ok(Self { transport, from })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), Error> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .body(body.to_string())?;
+        self.transport.send(&message)?;
+        Ok(())
+    }
+}
+
+/// Stand-in for local development and tests, writes every message into `dir` or to the log.
+pub struct FileMailer {
+    dir: Option<PathBuf>,
+}
+
+impl FileMailer {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), Error> {
+        let message = format!("To: {to}\nSubject: {subject}\n\n{body}\n");
+        match &self.dir {
+            Some(dir) => {
+                fs::write(dir.join(format!("{}.eml", Uuid::new_v4())), message).map_err(|e| {
+                    error!("{e:?}");
+                    Error::InternalServerError
+                })
+            }
+            None => {
+                info!("{message}");
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn mailer() -> Result<Box<dyn Mailer>, Error> {
+    match smtp_url() {
+        Some(url) => Ok(Box::new(SmtpMailer::new(&url, &mail_from())?)),
+        None => Ok(Box::new(FileMailer::new(mail_dir().map(PathBuf::from)))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_mailer_writes_message() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir(&dir).expect("Can not create directory");
+        let mailer = FileMailer::new(Some(dir.clone()));
+        mailer
+            .send("foo@bar.com", "Subject", "Body")
+            .expect("Can not send");
+        let entries = fs::read_dir(&dir)
+            .expect("Can not read directory")
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        let path = entries[0].as_ref().expect("Can not read entry").path();
+        let message = fs::read_to_string(path).expect("Can not read message");
+        assert!(message.contains("To: foo@bar.com"));
+        assert!(message.contains("Body"));
+        fs::remove_dir_all(dir).expect("Can not clean up");
+    }
+
+    #[test]
+    fn smtp_mailer_rejects_invalid_sender() {
+        let result = SmtpMailer::new("smtp://localhost:2525", "not an address");
+        assert!(result.is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use unpacking::config::{app_address, database_url, db_pool_size, load_and_validate_env_vars};
+use unpacking::db::snippets::update_search_language;
 use unpacking::db::users::create_admin_account_if_not_present;
 use unpacking::router::router;
 
@@ -28,6 +29,9 @@ async fn main() {
     repo.run(|mut conn| create_admin_account_if_not_present(&mut conn))
         .await
         .expect("Error creating the admin account");
+    repo.run(|mut conn| update_search_language(&mut conn))
+        .await
+        .expect("Error updating the search language");
 
     let server = gotham::init_server(app_address(), router(repo.clone()));
 
@@ -0,0 +1,306 @@
+use crate::authentication::{generate_token, hash_token};
+use crate::config::refresh_token_ttl_days;
+use crate::db::{access_tokens, api_keys};
+use crate::jwt::keyring;
+use crate::resources::api_key::API_KEY_PREFIX;
+use crate::resources::auth::{AuthData, TokenResponse, ACCESS_TOKEN_MINUTES};
+use crate::router::{Repo, API_URL};
+
+use futures::prelude::*;
+use gotham::handler::HandlerFuture;
+use gotham::hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, COOKIE, SET_COOKIE};
+use gotham::hyper::Method;
+use gotham::middleware::Middleware;
+use gotham::state::{FromState, State};
+use gotham_derive::{NewMiddleware, StateData};
+use gotham_restful::{AuthSource, AuthStatus};
+use jsonwebtoken::errors::ErrorKind;
+use log::error;
+use std::pin::Pin;
+
+pub const SESSION_COOKIE: &str = "session";
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+pub const REFRESH_COOKIE: &str = "refresh_token";
+/// Where the refresh token cookie is sent, below the API.
+pub const REFRESH_PATH: &str = "/auth/refresh";
+const COOKIE_ATTRIBUTES: &str = "Secure; SameSite=Strict";
+
+/// Whether sessions are kept in cookies, as with `COOKIE_AUTH`. Put into the state for handlers.
+#[derive(Clone, Copy, StateData)]
+pub struct CookieAuth(pub bool);
+
+impl CookieAuth {
+    pub fn enabled(state: &State) -> bool {
+        CookieAuth::try_borrow_from(state).is_some_and(|cookie_auth| cookie_auth.0)
+    }
+}
+
+/// Verifies JWTs against the keyring, which picks the key named by the `kid` header. They are
+/// taken from the bearer header, or with cookie auth also from the session cookie.
+/// Takes the place of `gotham_restful::AuthMiddleware`, that only supports shared secrets.
+#[derive(Clone, NewMiddleware)]
+pub struct JwtMiddleware {
+    pub cookie_auth: CookieAuth,
+}
+
+impl Middleware for JwtMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let token = match bearer(&state) {
+            Some(token) => Ok(Some(token)),
+            None if self.cookie_auth.0 => csrf_protected_cookie(
+                HeaderMap::borrow_from(&state),
+                Method::borrow_from(&state),
+                SESSION_COOKIE,
+            ),
+            None => Ok(None),
+        };
+        let status = match token {
+            Ok(Some(token)) => match keyring().decode::<AuthData>(token) {
+                Ok(data) => AuthStatus::Authenticated(data),
+                Err(e) => AuthStatus::Invalid(e),
+            },
+            Ok(None) => AuthStatus::Unauthenticated,
+            Err(e) => AuthStatus::Invalid(e),
+        };
+        state.put(status);
+        state.put(self.cookie_auth);
+        // Read by the OpenAPI handler to describe the security scheme
+        state.put(AuthSource::AuthorizationHeader);
+        chain(state)
+    }
+}
+
+/// Rejects tokens that passed signature and expiry checks but were revoked since they were issued.
+/// Has to be added to the pipeline after both the diesel and the auth middleware.
+#[derive(Clone, NewMiddleware)]
+pub struct RevocationMiddleware;
+
+impl Middleware for RevocationMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        async move {
+            let jti = match AuthStatus::<AuthData>::try_borrow_from(&state) {
+                Some(AuthStatus::Authenticated(data)) => Some(data.jti()),
+                _ => None,
+            };
+            if let Some(jti) = jti {
+                let repo = Repo::borrow_from(&state).clone();
+                let active = repo
+                    .run(move |mut conn| access_tokens::is_active(jti, &mut conn))
+                    .await;
+                if active != Ok(true) {
+                    state.put(AuthStatus::<AuthData>::Invalid(
+                        ErrorKind::InvalidToken.into(),
+                    ));
+                }
+            }
+            chain(state).await
+        }
+        .boxed()
+    }
+}
+
+/// Authenticates bearer tokens that are personal API keys rather than JWTs, so permission checks
+/// treat both alike. Has to be added to the pipeline after the revocation middleware.
+#[derive(Clone, NewMiddleware)]
+pub struct ApiKeyMiddleware;
+
+impl Middleware for ApiKeyMiddleware {
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        async move {
+            let key = bearer(&state)
+                .filter(|token| token.starts_with(API_KEY_PREFIX))
+                .map(hash_token);
+            if let Some(key_hash) = key {
+                let repo = Repo::borrow_from(&state).clone();
+                let result = repo
+                    .run(move |mut conn| api_keys::authenticate(&key_hash, &mut conn))
+                    .await;
+                let status = match result {
+                    Ok((key, role)) => AuthStatus::Authenticated(AuthData::api_key(&key, role)),
+                    Err(_) => AuthStatus::Invalid(ErrorKind::InvalidToken.into()),
+                };
+                state.put(status);
+            }
+            chain(state).await
+        }
+        .boxed()
+    }
+}
+
+/// Cookies to set on the response, put into the state by handlers.
+#[derive(Default, StateData)]
+pub struct ResponseCookies(Vec<String>);
+
+impl ResponseCookies {
+    /// Sets the session cookie with the JWT, the refresh token cookie that only the refresh
+    /// endpoint receives, and a new CSRF token for the frontend to repeat in the `X-CSRF-Token`
+    /// header. The refresh token is taken out of the response. Does nothing without cookie auth.
+    pub fn start_session(state: &mut State, tokens: &mut TokenResponse) {
+        if !CookieAuth::enabled(state) {
+            return;
+        }
+        let max_age = ACCESS_TOKEN_MINUTES * 60;
+        let mut cookies = vec![
+            format!(
+                "{SESSION_COOKIE}={}; Path={API_URL}; Max-Age={max_age}; HttpOnly; {COOKIE_ATTRIBUTES}",
+                tokens.token()
+            ),
+            format!(
+                "{CSRF_COOKIE}={}; Path=/; Max-Age={max_age}; {COOKIE_ATTRIBUTES}",
+                generate_token()
+            ),
+        ];
+        if let Some(refresh_token) = tokens.take_refresh_token() {
+            cookies.push(format!(
+                "{REFRESH_COOKIE}={refresh_token}; Path={API_URL}{REFRESH_PATH}; Max-Age={}; HttpOnly; {COOKIE_ATTRIBUTES}",
+                refresh_token_ttl_days() * 24 * 60 * 60
+            ));
+        }
+        state.put(Self(cookies));
+    }
+
+    pub fn end_session(state: &mut State) {
+        if !CookieAuth::enabled(state) {
+            return;
+        }
+        let cookies = Self(vec![
+            format!("{SESSION_COOKIE}=; Path={API_URL}; Max-Age=0; HttpOnly; {COOKIE_ATTRIBUTES}"),
+            format!(
+                "{REFRESH_COOKIE}=; Path={API_URL}{REFRESH_PATH}; Max-Age=0; HttpOnly; {COOKIE_ATTRIBUTES}"
+            ),
+            format!("{CSRF_COOKIE}=; Path=/; Max-Age=0; {COOKIE_ATTRIBUTES}"),
+        ]);
+        state.put(cookies);
+    }
+}
+
+/// Adds the `ResponseCookies` that handlers put into the state to their response.
+#[derive(Clone, NewMiddleware)]
+pub struct CookieMiddleware;
+
+impl Middleware for CookieMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+    where
+        Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        chain(state)
+            .map_ok(|(mut state, mut response)| {
+                if let Some(cookies) = ResponseCookies::try_take_from(&mut state) {
+                    for cookie in cookies.0 {
+                        match HeaderValue::from_str(&cookie) {
+                            Ok(value) => {
+                                response.headers_mut().append(SET_COOKIE, value);
+                            }
+                            Err(e) => error!("Cookie: {e:?}"),
+                        }
+                    }
+                }
+                (state, response)
+            })
+            .boxed()
+    }
+}
+
+/// The token of a cookie. Requests that change state have to repeat the CSRF cookie in the
+/// `X-CSRF-Token` header, which other sites can neither read nor set.
+pub(crate) fn csrf_protected_cookie<'a>(
+    headers: &'a HeaderMap,
+    method: &Method,
+    name: &str,
+) -> Result<Option<&'a str>, jsonwebtoken::errors::Error> {
+    let Some(token) = cookie(headers, name) else {
+        return Ok(None);
+    };
+    if method.is_safe() {
+        return Ok(Some(token));
+    }
+    let header = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (cookie(headers, CSRF_COOKIE), header) {
+        (Some(expected), Some(header)) if !expected.is_empty() && expected == header => {
+            Ok(Some(token))
+        }
+        _ => Err(ErrorKind::InvalidToken.into()),
+    }
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn bearer(state: &State) -> Option<&str> {
+    HeaderMap::borrow_from(state)
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).expect("Invalid header"));
+        }
+        headers
+    }
+
+    #[test]
+    fn cookies_are_found_by_name() {
+        let headers = headers(&[("cookie", "a=1; session=token"), ("cookie", "b=2")]);
+        assert_eq!(cookie(&headers, SESSION_COOKIE), Some("token"));
+        assert_eq!(cookie(&headers, "b"), Some("2"));
+        assert_eq!(cookie(&headers, CSRF_COOKIE), None);
+    }
+
+    #[test]
+    fn state_changes_need_csrf_token() {
+        let without = headers(&[("cookie", "session=token; csrf_token=csrf")]);
+        assert_eq!(
+            csrf_protected_cookie(&without, &Method::GET, SESSION_COOKIE).ok(),
+            Some(Some("token"))
+        );
+        assert!(csrf_protected_cookie(&without, &Method::POST, SESSION_COOKIE).is_err());
+
+        let wrong = headers(&[
+            ("cookie", "session=token; csrf_token=csrf"),
+            (CSRF_HEADER, "other"),
+        ]);
+        assert!(csrf_protected_cookie(&wrong, &Method::DELETE, SESSION_COOKIE).is_err());
+
+        let repeated = headers(&[
+            ("cookie", "session=token; csrf_token=csrf"),
+            (CSRF_HEADER, "csrf"),
+        ]);
+        assert_eq!(
+            csrf_protected_cookie(&repeated, &Method::PUT, SESSION_COOKIE).ok(),
+            Some(Some("token"))
+        );
+
+        let none = headers(&[("cookie", "csrf_token=csrf")]);
+        assert_eq!(
+            csrf_protected_cookie(&none, &Method::POST, SESSION_COOKIE).ok(),
+            Some(None)
+        );
+    }
+}
@@ -3,7 +3,7 @@ use crate::schema::*;
 use chrono::{NaiveDateTime, Utc};
 use derive_getters::{Dissolve, Getters};
 use diesel::{Identifiable, Insertable, Queryable};
-use enums::{Media, Role};
+use enums::{LoginSubject, Media, Role};
 use uuid::Uuid;
 
 #[cfg(test)]
@@ -33,11 +33,32 @@ pub mod enums {
         Website,
     }
 
-    #[derive(Clone, Copy, Debug, PartialEq, Eq, DbEnum, Serialize, Deserialize, OpenapiType)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, DbEnum)]
+    #[ExistingTypePath = "crate::schema::sql_types::LoginSubjectEnum"]
+    pub enum LoginSubject {
+        Email,
+        Ip,
+    }
+
+    /// Declared from least to most privileged, so roles can be compared.
+    #[derive(
+        Clone,
+        Copy,
+        Debug,
+        PartialEq,
+        Eq,
+        PartialOrd,
+        Ord,
+        DbEnum,
+        Serialize,
+        Deserialize,
+        OpenapiType,
+    )]
     #[ExistingTypePath = "crate::schema::sql_types::RoleEnum"]
     #[cfg_attr(test, derive(Dummy))]
     pub enum Role {
         User,
+        Editor,
         Admin,
     }
 }
@@ -99,6 +120,333 @@ impl Password {
     }
 }
 
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(belongs_to(User))]
+pub struct ApiKey {
+    id: Uuid,
+    user_id: Uuid,
+    name: String,
+    prefix: String,
+    key_hash: String,
+    role: Role,
+    expires_at: Option<NaiveDateTime>,
+    last_used_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+    scopes: Vec<String>,
+}
+
+impl ApiKey {
+    pub fn new(
+        user_id: Uuid,
+        name: String,
+        prefix: String,
+        key_hash: String,
+        role: Role,
+        expires_at: Option<NaiveDateTime>,
+        scopes: Vec<String>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            name,
+            prefix,
+            key_hash,
+            role,
+            expires_at,
+            last_used_at: None,
+            revoked_at: None,
+            created_at: Utc::now().naive_utc(),
+            scopes,
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(primary_key(subject, identifier))]
+pub struct LoginAttempt {
+    subject: LoginSubject,
+    identifier: String,
+    failures: i32,
+    last_failed_at: NaiveDateTime,
+    locked_until: Option<NaiveDateTime>,
+}
+
+impl LoginAttempt {
+    pub fn new(
+        subject: LoginSubject,
+        identifier: String,
+        failures: i32,
+        locked_until: Option<NaiveDateTime>,
+    ) -> Self {
+        Self {
+            subject,
+            identifier,
+            failures,
+            last_failed_at: Utc::now().naive_utc(),
+            locked_until,
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(belongs_to(User))]
+pub struct PasswordReset {
+    id: Uuid,
+    user_id: Uuid,
+    token_hash: String,
+    expires_at: NaiveDateTime,
+    used_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl PasswordReset {
+    pub fn new(user_id: Uuid, token_hash: String, expires_at: NaiveDateTime) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            token_hash,
+            expires_at,
+            used_at: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+pub struct Invitation {
+    id: Uuid,
+    email: String,
+    role: Role,
+    token_hash: String,
+    invited_by: Option<Uuid>,
+    expires_at: NaiveDateTime,
+    accepted_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl Invitation {
+    pub fn new(
+        email: String,
+        role: Role,
+        token_hash: String,
+        invited_by: Uuid,
+        expires_at: NaiveDateTime,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            email,
+            role,
+            token_hash,
+            invited_by: Some(invited_by),
+            expires_at,
+            accepted_at: None,
+            revoked_at: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(belongs_to(User))]
+#[diesel(primary_key(user_id))]
+pub struct TotpSecret {
+    user_id: Uuid,
+    secret: Vec<u8>,
+    last_used_step: Option<i64>,
+    confirmed_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl TotpSecret {
+    pub fn new(user_id: Uuid, secret: Vec<u8>) -> Self {
+        Self {
+            user_id,
+            secret,
+            last_used_step: None,
+            confirmed_at: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(belongs_to(User))]
+pub struct RecoveryCode {
+    id: Uuid,
+    user_id: Uuid,
+    code_hash: String,
+    used_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl RecoveryCode {
+    pub fn new(user_id: Uuid, code_hash: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            code_hash,
+            used_at: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(belongs_to(User))]
+pub struct LoginChallenge {
+    id: Uuid,
+    user_id: Uuid,
+    token_hash: String,
+    expires_at: NaiveDateTime,
+    used_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl LoginChallenge {
+    pub fn new(user_id: Uuid, token_hash: String, expires_at: NaiveDateTime) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            token_hash,
+            expires_at,
+            used_at: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+pub struct OidcLogin {
+    id: Uuid,
+    state_hash: String,
+    nonce: String,
+    code_verifier: String,
+    expires_at: NaiveDateTime,
+    created_at: NaiveDateTime,
+}
+
+impl OidcLogin {
+    pub fn new(
+        state_hash: String,
+        nonce: String,
+        code_verifier: String,
+        expires_at: NaiveDateTime,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            state_hash,
+            nonce,
+            code_verifier,
+            expires_at,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(belongs_to(User))]
+#[diesel(primary_key(issuer, subject))]
+#[diesel(table_name = user_identities)]
+pub struct UserIdentity {
+    issuer: String,
+    subject: String,
+    user_id: Uuid,
+    created_at: NaiveDateTime,
+}
+
+impl UserIdentity {
+    pub fn new(issuer: String, subject: String, user_id: Uuid) -> Self {
+        Self {
+            issuer,
+            subject,
+            user_id,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(belongs_to(User))]
+#[diesel(primary_key(jti))]
+pub struct AccessToken {
+    jti: Uuid,
+    user_id: Uuid,
+    family_id: Uuid,
+    expires_at: NaiveDateTime,
+    revoked_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl AccessToken {
+    pub fn new(user_id: Uuid, family_id: Uuid, expires_at: NaiveDateTime) -> Self {
+        Self {
+            jti: Uuid::new_v4(),
+            user_id,
+            family_id,
+            expires_at,
+            revoked_at: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(belongs_to(User))]
+pub struct RefreshToken {
+    id: Uuid,
+    user_id: Uuid,
+    family_id: Uuid,
+    token_hash: String,
+    expires_at: NaiveDateTime,
+    used_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl RefreshToken {
+    pub fn new(
+        user_id: Uuid,
+        family_id: Uuid,
+        token_hash: String,
+        expires_at: NaiveDateTime,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            family_id,
+            token_hash,
+            expires_at,
+            used_at: None,
+            revoked_at: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
+#[diesel(belongs_to(User))]
+pub struct Session {
+    id: Uuid,
+    user_id: Uuid,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    created_at: NaiveDateTime,
+}
+
+impl Session {
+    pub fn new(id: Uuid, user_id: Uuid, ip: Option<String>, user_agent: Option<String>) -> Self {
+        Self {
+            id,
+            user_id,
+            ip,
+            user_agent,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
 #[derive(Identifiable, Insertable, Queryable, Debug, Getters, Dissolve)]
 #[cfg_attr(test, derive(Dummy))]
 pub struct Term {
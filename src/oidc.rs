@@ -0,0 +1,388 @@
+use crate::authentication::generate_token;
+use crate::config::{
+    oidc_client_id, oidc_client_secret, oidc_issuer, oidc_redirect_url, oidc_scopes,
+};
+use crate::error::Error;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{DecodingKey, Validation};
+use log::error;
+use serde_derive::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// An OpenID Connect provider signed in to with the authorization code flow and PKCE.
+pub struct Provider {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    scopes: String,
+    redirect_url: String,
+}
+
+/// The endpoints of a provider, from its discovery document.
+#[derive(Deserialize)]
+pub struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+/// What has to be kept until the provider redirects back, `state` is also part of the `url`.
+pub struct Authorization {
+    pub url: String,
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdClaims {
+    pub iss: String,
+    pub sub: String,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub name: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Provider {
+    pub fn new(
+        issuer: String,
+        client_id: String,
+        client_secret: Option<String>,
+        scopes: String,
+        redirect_url: String,
+    ) -> Self {
+        Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret,
+            scopes,
+            redirect_url,
+        }
+    }
+
+    pub fn from_config() -> Option<Self> {
+        oidc_issuer().map(|issuer| {
+            Self::new(
+                issuer,
+                oidc_client_id(),
+                oidc_client_secret(),
+                oidc_scopes(),
+                oidc_redirect_url(),
+            )
+        })
+    }
+
+    pub async fn discover(&self) -> Result<Discovery, Error> {
+        let url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let discovery = reqwest::get(url)
+            .await?
+            .error_for_status()?
+            .json::<Discovery>()
+            .await?;
+        if discovery.issuer.trim_end_matches('/') != self.issuer {
+            error!("OIDC: discovered issuer {}", discovery.issuer);
+            return Err(Error::InternalServerError);
+        }
+        Ok(discovery)
+    }
+
+    pub fn authorize(&self, discovery: &Discovery) -> Result<Authorization, Error> {
+        let state = generate_token();
+        let nonce = generate_token();
+        let code_verifier = generate_token();
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(&code_verifier));
+        let url = Url::parse_with_params(
+            &discovery.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &self.redirect_url),
+                ("scope", &self.scopes),
+                ("state", &state),
+                ("nonce", &nonce),
+                ("code_challenge", &code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| {
+            error!("OIDC: {e:?}");
+            Error::InternalServerError
+        })?;
+        Ok(Authorization {
+            url: url.to_string(),
+            state,
+            nonce,
+            code_verifier,
+        })
+    }
+
+    /// Redeems the authorization code and verifies the ID token it is exchanged for.
+    pub async fn exchange(
+        &self,
+        discovery: &Discovery,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdClaims, Error> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.redirect_url),
+            ("client_id", &self.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = reqwest::Client::new()
+            .post(&discovery.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        if response.status().is_client_error() {
+            error!("OIDC: token endpoint responded {}", response.status());
+            return Err(Error::Unauthorized);
+        }
+        let tokens = response.error_for_status()?.json::<TokenResponse>().await?;
+        self.verify(discovery, &tokens.id_token, nonce).await
+    }
+
+    async fn verify(
+        &self,
+        discovery: &Discovery,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<IdClaims, Error> {
+        let jwks = reqwest::get(&discovery.jwks_uri)
+            .await?
+            .error_for_status()?
+            .json::<JwkSet>()
+            .await?;
+        let claims = decode(&jwks, id_token, &discovery.issuer, &self.client_id).map_err(|e| {
+            error!("OIDC: {e:?}");
+            Error::Unauthorized
+        })?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(Error::Unauthorized);
+        }
+        Ok(claims)
+    }
+}
+
+fn decode(
+    jwks: &JwkSet,
+    id_token: &str,
+    issuer: &str,
+    client_id: &str,
+) -> Result<IdClaims, jsonwebtoken::errors::Error> {
+    let header = jsonwebtoken::decode_header(id_token)?;
+    let jwk = header
+        .kid
+        .as_deref()
+        .and_then(|kid| jwks.find(kid))
+        .ok_or(jsonwebtoken::errors::ErrorKind::InvalidSignature)?;
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[issuer]);
+    validation.set_audience(&[client_id]);
+    let key = DecodingKey::from_jwk(jwk)?;
+    jsonwebtoken::decode::<IdClaims>(id_token, &key, &validation).map(|data| data.claims)
+}
+
+#[cfg(test)]
+pub mod mock {
+    //! A local issuer signing ID tokens with an Ed25519 key, for tests of the sign in.
+
+    use crate::jwt::Keyring;
+
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use gotham::hyper::service::{make_service_fn, service_fn};
+    use gotham::hyper::{Body, Request, Response, Server, StatusCode};
+    use ring::rand::SystemRandom;
+    use ring::signature::Ed25519KeyPair;
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    pub const CLIENT_ID: &str = "unpacking";
+    pub const CODE: &str = "authorization-code";
+
+    /// The authorization request the issuer expects a code exchange for.
+    #[derive(Default)]
+    pub struct Pending {
+        pub nonce: String,
+        pub code_challenge: String,
+        pub sub: String,
+        pub email: String,
+    }
+
+    pub struct MockIssuer {
+        pub url: String,
+        pub pending: Arc<Mutex<Pending>>,
+    }
+
+    pub async fn start() -> MockIssuer {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).expect("Can not generate");
+        let keyring =
+            Arc::new(Keyring::new(pkcs8.as_ref(), vec![]).expect("Can not build keyring"));
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Can not bind");
+        let addr: SocketAddr = listener.local_addr().expect("Can not find address");
+        let url = format!("http://{addr}");
+        let state = (url.clone(), keyring, pending.clone());
+        let service = make_service_fn(move |_| {
+            let state = state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(respond(request, state).await) }
+                }))
+            }
+        });
+        let server = Server::from_tcp(listener)
+            .expect("Can not serve")
+            .serve(service);
+        tokio::spawn(server);
+        MockIssuer { url, pending }
+    }
+
+    async fn respond(
+        request: Request<Body>,
+        (url, keyring, pending): (String, Arc<Keyring>, Arc<Mutex<Pending>>),
+    ) -> Response<Body> {
+        let body = match request.uri().path() {
+            "/.well-known/openid-configuration" => json!({
+                "issuer": url,
+                "authorization_endpoint": format!("{url}/authorize"),
+                "token_endpoint": format!("{url}/token"),
+                "jwks_uri": format!("{url}/jwks"),
+            }),
+            "/jwks" => serde_json::to_value(keyring.jwks()).expect("Can not encode"),
+            "/token" => {
+                let body = gotham::hyper::body::to_bytes(request.into_body())
+                    .await
+                    .expect("Can not read body");
+                let form = url::form_urlencoded::parse(&body)
+                    .into_owned()
+                    .collect::<HashMap<_, _>>();
+                let pending = pending.lock().expect("Can not lock");
+                let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+                let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier));
+                if form.get("code").map(String::as_str) != Some(CODE)
+                    || challenge != pending.code_challenge
+                {
+                    return Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from(json!({"error": "invalid_grant"}).to_string()))
+                        .expect("Can not respond");
+                }
+                let claims = json!({
+                    "iss": url,
+                    "aud": CLIENT_ID,
+                    "sub": pending.sub,
+                    "email": pending.email,
+                    "email_verified": true,
+                    "name": "Signed In",
+                    "nonce": pending.nonce,
+                    "exp": chrono::Utc::now().timestamp() + 60,
+                });
+                let id_token = keyring.encode(&claims).expect("Can not sign");
+                json!({"id_token": id_token, "access_token": "unused", "token_type": "Bearer"})
+            }
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .expect("Can not respond")
+            }
+        };
+        Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .expect("Can not respond")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn provider(issuer: &str) -> Provider {
+        Provider::new(
+            issuer.to_string(),
+            mock::CLIENT_ID.to_string(),
+            None,
+            "openid email".to_string(),
+            "http://localhost:3000/oidc/callback".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn code_is_exchanged_for_verified_claims() {
+        let issuer = mock::start().await;
+        let provider = provider(&issuer.url);
+        let discovery = provider.discover().await.expect("Can not discover");
+        let authorization = provider.authorize(&discovery).expect("Can not authorize");
+        let query = Url::parse(&authorization.url)
+            .expect("Can not parse")
+            .query_pairs()
+            .into_owned()
+            .collect::<HashMap<_, _>>();
+        assert_eq!(query["state"], authorization.state);
+        assert_eq!(query["code_challenge_method"], "S256");
+        {
+            let mut pending = issuer.pending.lock().expect("Can not lock");
+            pending.nonce = query["nonce"].clone();
+            pending.code_challenge = query["code_challenge"].clone();
+            pending.sub = "subject".to_string();
+            pending.email = "signed-in@example.com".to_string();
+        }
+
+        let result = provider
+            .exchange(
+                &discovery,
+                mock::CODE,
+                "wrong verifier",
+                &authorization.nonce,
+            )
+            .await;
+        assert_eq!(result.map(|_| ()), Err(Error::Unauthorized));
+
+        let result = provider
+            .exchange(
+                &discovery,
+                mock::CODE,
+                &authorization.code_verifier,
+                "other nonce",
+            )
+            .await;
+        assert_eq!(result.map(|_| ()), Err(Error::Unauthorized));
+
+        let claims = provider
+            .exchange(
+                &discovery,
+                mock::CODE,
+                &authorization.code_verifier,
+                &authorization.nonce,
+            )
+            .await
+            .expect("Can not exchange");
+        assert_eq!(claims.sub, "subject");
+        assert_eq!(claims.email.as_deref(), Some("signed-in@example.com"));
+    }
+}
@@ -0,0 +1,244 @@
+use crate::authentication::{generate_token, hash_token};
+use crate::db::api_keys;
+use crate::error::Error;
+use crate::models::{enums::Role, ApiKey};
+use crate::resources::auth::{AuthenticationStatus, Permission, Scope};
+use crate::resources::validation::api_keys::MIN_NAME_LENGTH;
+
+use chrono::NaiveDateTime;
+use diesel::PgConnection;
+use gotham_restful::*;
+use openapi_type::OpenapiType;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+
+/// Marks bearer tokens that are API keys, the JWTs never start with it.
+pub const API_KEY_PREFIX: &str = "upk_";
+
+/// Characters of a key kept in plain text, enough to tell keys apart.
+const DISPLAYED_LENGTH: usize = 12;
+
+#[derive(Resource)]
+#[resource(create, read_all, delete)]
+pub struct Resource;
+
+#[derive(Serialize, Deserialize, OpenapiType, Validate)]
+pub struct CreateApiKey {
+    #[validate(length(min = "MIN_NAME_LENGTH"))]
+    pub name: String,
+    /// Defaults to the role of the owner, and can not be higher than it.
+    pub role: Option<Role>,
+    /// Defaults to the scopes of the request creating the key, and can not exceed them.
+    pub scopes: Option<Vec<Scope>>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, OpenapiType)]
+pub struct CreatedApiKey {
+    pub id: Uuid,
+    /// Only ever returned here, store it safely.
+    pub key: String,
+}
+
+#[derive(Serialize, OpenapiType)]
+struct ApiKeyResponse {
+    id: Uuid,
+    name: String,
+    prefix: String,
+    role: Role,
+    scopes: Vec<Scope>,
+    expires_at: Option<NaiveDateTime>,
+    last_used_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl From<ApiKey> for ApiKeyResponse {
+    fn from(key: ApiKey) -> Self {
+        let (id, _, name, prefix, _, role, expires_at, last_used_at, _, created_at, scopes) =
+            key.dissolve();
+        Self {
+            id,
+            name,
+            prefix,
+            role,
+            scopes: scopes
+                .iter()
+                .filter_map(|scope| scope.parse().ok())
+                .collect(),
+            expires_at,
+            last_used_at,
+            created_at,
+        }
+    }
+}
+
+/// Not with an API key, so keys can not outlive their expiry or revocation through new keys.
+#[create]
+fn create(
+    auth: AuthenticationStatus,
+    body: CreateApiKey,
+    conn: &mut PgConnection,
+) -> Result<CreatedApiKey, Error> {
+    let auth = auth.ok().signed_in()?;
+    body.validate()?;
+    let scopes = body.scopes.unwrap_or_else(|| auth.scope().to_vec());
+    if scopes.iter().any(|scope| !auth.scope().contains(scope)) {
+        return Err(Error::Forbidden);
+    }
+    let key = format!("{API_KEY_PREFIX}{}", generate_token());
+    let api_key = ApiKey::new(
+        auth.sub(),
+        body.name,
+        key[..DISPLAYED_LENGTH].to_string(),
+        hash_token(&key),
+        body.role.unwrap_or(auth.role()),
+        body.expires_at,
+        scopes
+            .iter()
+            .map(|scope| scope.as_str().to_string())
+            .collect(),
+    );
+    let id = api_keys::insert(api_key, conn)?;
+    Ok(CreatedApiKey { id, key })
+}
+
+/// Not with an API key.
+#[read_all]
+fn read_all(
+    auth: AuthenticationStatus,
+    conn: &mut PgConnection,
+) -> Result<Vec<ApiKeyResponse>, Error> {
+    let auth = auth.ok().signed_in()?;
+    let keys = api_keys::select_all(auth.sub(), conn)?;
+    Ok(keys.into_iter().map(ApiKeyResponse::from).collect())
+}
+
+/// Not with an API key.
+#[delete]
+fn delete(
+    auth: AuthenticationStatus,
+    id: Uuid,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    let auth = auth.ok().signed_in()?;
+    api_keys::revoke(auth.sub(), id, conn).map(|_| NoContent::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::users::insert;
+    use crate::models::{Password, User};
+    use crate::resources::auth::generate_jwt;
+    use crate::router::router;
+    use fake::{Fake, Faker};
+    use gotham::hyper::header::AUTHORIZATION;
+    use gotham::mime::APPLICATION_JSON;
+    use gotham::plain::test::AsyncTestServer;
+    use gotham_restful::gotham::hyper::StatusCode;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn api_key_authenticates_requests() {
+        let user = User::fake(Role::User);
+        let password: Password = Faker.fake();
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let user_id = insert(user, password.password(), conn).expect("Can not create");
+            let token =
+                generate_jwt(user_id, Role::User, Uuid::new_v4(), conn).expect("Can not sign");
+            tx.send(token).expect("Can not send");
+        })
+        .await;
+        let token = rx.await.expect("Can not find token");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+
+        let request = CreateApiKey {
+            name: "Ingestion".to_string(),
+            role: Some(Role::Admin),
+            scopes: None,
+            expires_at: None,
+        };
+        let body = serde_json::to_string(&request).expect("Can not encode JSON");
+        let response = test_server
+            .client()
+            .post("http://localhost:3000/api/v1/keys")
+            .body(body)
+            .mime(APPLICATION_JSON)
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let request = CreateApiKey {
+            name: "Ingestion".to_string(),
+            role: None,
+            scopes: Some(vec![Scope::SnippetsRead]),
+            expires_at: None,
+        };
+        let body = serde_json::to_string(&request).expect("Can not encode JSON");
+        let response = test_server
+            .client()
+            .post("http://localhost:3000/api/v1/keys")
+            .body(body)
+            .mime(APPLICATION_JSON)
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let created: CreatedApiKey = serde_json::from_slice(&body).expect("Can not decode JSON");
+        assert!(created.key.starts_with(API_KEY_PREFIX));
+
+        for (key, path, status) in [
+            (created.key.clone(), "keys", StatusCode::FORBIDDEN),
+            (created.key.clone(), "snippets", StatusCode::OK),
+            (created.key.clone(), "terms", StatusCode::FORBIDDEN),
+            (
+                format!("{API_KEY_PREFIX}unknown"),
+                "keys",
+                StatusCode::UNAUTHORIZED,
+            ),
+        ] {
+            let response = test_server
+                .client()
+                .get(format!("http://localhost:3000/api/v1/{path}"))
+                .header(AUTHORIZATION, format!("Bearer {key}"))
+                .perform()
+                .await
+                .expect("Can not send a request");
+            assert_eq!(response.status(), status);
+        }
+
+        let body = serde_json::to_string(&request).expect("Can not encode JSON");
+        let response = test_server
+            .client()
+            .post("http://localhost:3000/api/v1/keys")
+            .body(body)
+            .mime(APPLICATION_JSON)
+            .header(AUTHORIZATION, format!("Bearer {}", created.key))
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        for path in [format!("keys/{}", created.id), "auth".to_string()] {
+            let response = test_server
+                .client()
+                .delete(format!("http://localhost:3000/api/v1/{path}"))
+                .header(AUTHORIZATION, format!("Bearer {}", created.key))
+                .perform()
+                .await
+                .expect("Can not send a request");
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+    }
+}
@@ -1,34 +1,197 @@
-use crate::config::jwt_secret;
-use crate::db::users::authenticate;
+use crate::authentication::{generate_token, hash_token};
+use crate::config::{app_url, client_ip_header, require_admin_2fa};
+use crate::db::users::{self, authenticate, select_by_email};
+use crate::db::{
+    access_tokens, login_attempts, login_challenges, password_resets, refresh_tokens, sessions,
+    two_factor,
+};
 use crate::error::Error;
-use crate::models::enums::Role;
+use crate::jwt::keyring;
+use crate::mailer::mailer;
+use crate::middleware::{csrf_protected_cookie, CookieAuth, ResponseCookies, REFRESH_COOKIE};
+use crate::models::{enums::Role, ApiKey, Session};
+use crate::resources::two_factor::{enrol, TotpEnrolment};
 use crate::resources::validation::users::*;
+use crate::router::Repo;
 
-use chrono::Utc;
-use diesel::PgConnection;
+use chrono::{Duration, Utc};
+use diesel::{Connection, PgConnection};
+use gotham_derive::{StateData, StaticResponseExtender};
+use gotham_restful::gotham::hyper::header::USER_AGENT;
+use gotham_restful::gotham::hyper::{HeaderMap, Method};
+use gotham_restful::gotham::state::{client_addr, FromState, State};
 use gotham_restful::*;
-use jsonwebtoken::{encode, EncodingKey, Header};
-use openapi_type::OpenapiType;
+use log::error;
+use openapi_type::{OpenapiType, Visitor};
 use serde_derive::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::str::FromStr;
 use uuid::Uuid;
 use validator::Validate;
 
+/// How long an access token, and the session cookie holding it, stays valid.
+pub(crate) const ACCESS_TOKEN_MINUTES: i64 = 30;
+
 #[derive(Resource)]
-#[resource(auth)]
+#[resource(auth, refresh, logout, forgot_password, reset_password, clear_lockout)]
 pub struct Resource;
 
+/// What a token is allowed to do. Granted by role, API keys can be restricted to fewer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "snippets:read")]
+    SnippetsRead,
+    #[serde(rename = "snippets:write")]
+    SnippetsWrite,
+    #[serde(rename = "terms:read")]
+    TermsRead,
+    #[serde(rename = "terms:write")]
+    TermsWrite,
+    #[serde(rename = "authors:read")]
+    AuthorsRead,
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+}
+
+impl Scope {
+    const ALL: [Scope; 7] = [
+        Scope::SnippetsRead,
+        Scope::SnippetsWrite,
+        Scope::TermsRead,
+        Scope::TermsWrite,
+        Scope::AuthorsRead,
+        Scope::UsersRead,
+        Scope::UsersWrite,
+    ];
+
+    pub fn granted_to(role: Role) -> Vec<Scope> {
+        let mut scopes = vec![Scope::SnippetsRead, Scope::TermsRead, Scope::AuthorsRead];
+        if role >= Role::Editor {
+            scopes.extend([Scope::SnippetsWrite, Scope::TermsWrite]);
+        }
+        if role >= Role::Admin {
+            scopes.extend([Scope::UsersRead, Scope::UsersWrite]);
+        }
+        scopes
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::SnippetsRead => "snippets:read",
+            Scope::SnippetsWrite => "snippets:write",
+            Scope::TermsRead => "terms:read",
+            Scope::TermsWrite => "terms:write",
+            Scope::AuthorsRead => "authors:read",
+            Scope::UsersRead => "users:read",
+            Scope::UsersWrite => "users:write",
+        }
+    }
+}
+
+/// Implemented by hand since the derive does not pick up the renamed variants.
+impl OpenapiType for Scope {
+    fn visit_type<V: Visitor>(visitor: &mut V) {
+        visitor.visit_enum(
+            Some("Scope".to_string()),
+            Some("What a token is allowed to do.".to_string()),
+            Scope::ALL.iter().map(|scope| scope.as_str().to_string()),
+        );
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| format!("Unknown scope {s}"))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct AuthData {
     sub: Uuid,
     role: Role,
+    #[serde(with = "scope_claim")]
+    scope: Vec<Scope>,
+    jti: Uuid,
+    sid: Uuid,
     exp: u64,
+    /// Whether a personal API key was used instead of a token of a sign in.
+    #[serde(skip)]
+    api_key: bool,
+}
+
+/// Space separated like the `scope` claim of OAuth 2.0 access tokens, see
+/// https://datatracker.ietf.org/doc/html/rfc9068#section-2.2.3
+mod scope_claim {
+    use super::Scope;
+    use serde::de::{Deserializer, Error};
+    use serde::{Deserialize, Serializer};
+
+    pub fn serialize<S: Serializer>(scopes: &[Scope], serializer: S) -> Result<S::Ok, S::Error> {
+        let claim = scopes.iter().map(Scope::as_str).collect::<Vec<_>>();
+        serializer.serialize_str(&claim.join(" "))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Scope>, D::Error> {
+        let claim = String::deserialize(deserializer)?;
+        claim
+            .split_whitespace()
+            .map(|scope| scope.parse().map_err(D::Error::custom))
+            .collect()
+    }
 }
 
 impl AuthData {
-    fn is_admin(&self) -> Result<Uuid, Error> {
-        match self.role {
-            Role::Admin => Ok(self.sub),
-            Role::User => Err(Error::Forbidden),
+    /// Claims of a request authenticated with a personal API key instead of a JWT. The key keeps
+    /// only those of its scopes that the role still grants.
+    pub fn api_key(key: &ApiKey, role: Role) -> Self {
+        Self {
+            sub: *key.user_id(),
+            role,
+            scope: Scope::granted_to(role)
+                .into_iter()
+                .filter(|scope| key.scopes().iter().any(|s| s == scope.as_str()))
+                .collect(),
+            jti: *key.id(),
+            sid: *key.id(),
+            exp: key.expires_at().map_or(u64::MAX, |expires_at| {
+                expires_at.and_utc().timestamp() as u64
+            }),
+            api_key: true,
+        }
+    }
+
+    pub fn sub(&self) -> Uuid {
+        self.sub
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn scope(&self) -> &[Scope] {
+        &self.scope
+    }
+
+    pub fn jti(&self) -> Uuid {
+        self.jti
+    }
+
+    pub fn sid(&self) -> Uuid {
+        self.sid
+    }
+
+    fn has_scope(&self, scope: Scope) -> Result<Uuid, Error> {
+        if self.scope.contains(&scope) {
+            Ok(self.sub)
+        } else {
+            Err(Error::Forbidden)
         }
     }
 
@@ -42,21 +205,52 @@ impl AuthData {
 }
 
 pub trait Permission {
-    fn admin(self) -> Result<Uuid, Error>;
+    fn scope(self, scope: Scope) -> Result<Uuid, Error>;
     fn user(self, user_id: Uuid) -> Result<Uuid, Error>;
+    fn user_write(self, user_id: Uuid) -> Result<Uuid, Error>;
+    fn owner(self, user_id: Uuid) -> Result<AuthData, Error>;
+    fn signed_in(self) -> Result<AuthData, Error>;
 }
 
 impl Permission for Result<AuthData, AuthError> {
-    fn admin(self) -> Result<Uuid, Error> {
+    fn scope(self, scope: Scope) -> Result<Uuid, Error> {
         match self {
-            Ok(auth) => auth.is_admin(),
+            Ok(auth) => auth.has_scope(scope),
             Err(err) => Err(err.into()),
         }
     }
 
     fn user(self, user_id: Uuid) -> Result<Uuid, Error> {
         match self {
-            Ok(auth) => auth.is_admin().or_else(|_| auth.user_ok(user_id)),
+            Ok(auth) => auth
+                .has_scope(Scope::UsersRead)
+                .or_else(|_| auth.user_ok(user_id)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// API keys need the scope for their own user as well.
+    fn user_write(self, user_id: Uuid) -> Result<Uuid, Error> {
+        match self {
+            Ok(auth) if auth.api_key => auth.has_scope(Scope::UsersWrite),
+            Ok(auth) => auth
+                .has_scope(Scope::UsersWrite)
+                .or_else(|_| auth.user_ok(user_id)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The own account can not be changed with an API key, whatever its scopes.
+    fn owner(self, user_id: Uuid) -> Result<AuthData, Error> {
+        self.signed_in()
+            .and_then(|auth| auth.user_ok(user_id).map(|_| auth))
+    }
+
+    /// Rejects API keys, for what only a sign in may do.
+    fn signed_in(self) -> Result<AuthData, Error> {
+        match self {
+            Ok(auth) if auth.api_key => Err(Error::Forbidden),
+            Ok(auth) => Ok(auth),
             Err(err) => Err(err.into()),
         }
     }
@@ -72,39 +266,320 @@ struct CreateToken {
     password: String,
 }
 
+#[derive(Deserialize, Serialize, OpenapiType)]
+struct RotateToken {
+    /// Taken from its cookie when left out with `COOKIE_AUTH`.
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, OpenapiType, Validate)]
+struct ForgotPassword {
+    #[validate(length(min = "MIN_EMAIL_LENGTH"))]
+    email: String,
+}
+
+#[derive(Deserialize, Serialize, OpenapiType, Validate)]
+struct RedeemPasswordReset {
+    token: String,
+    #[validate(length(min = "MIN_PASSWORD_LENGTH"))]
+    password: String,
+}
+
 #[derive(Debug, Deserialize, Serialize, OpenapiType)]
 pub struct TokenResponse {
     id: Uuid,
     token: String,
+    /// Left out with `COOKIE_AUTH`, an HttpOnly cookie holds it instead.
+    refresh_token: Option<String>,
     role: Role,
+    /// Only when the sign in completed an enrolment that `REQUIRE_ADMIN_2FA` forced.
+    recovery_codes: Option<Vec<String>>,
+}
+
+impl TokenResponse {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub(crate) fn take_refresh_token(&mut self) -> Option<String> {
+        self.refresh_token.take()
+    }
+
+    pub(crate) fn with_recovery_codes(self, recovery_codes: Option<Vec<String>>) -> Self {
+        Self {
+            recovery_codes,
+            ..self
+        }
+    }
+}
+
+/// A second factor is needed when the user enabled it, or has to enrol as an admin when
+/// `REQUIRE_ADMIN_2FA` is set. The enrolment is only present in the latter case.
+#[derive(Debug, Deserialize, Serialize, OpenapiType)]
+pub struct ChallengeResponse {
+    pub challenge: String,
+    pub enrolment: Option<TotpEnrolment>,
 }
 
+#[derive(Debug, Deserialize, Serialize, OpenapiType)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Token(TokenResponse),
+    Challenge(ChallengeResponse),
+}
+
+/// Where a sign in comes from, recorded with the session it starts.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Client {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Client {
+    /// Takes the address from `CLIENT_IP_HEADER` when it is set, as the peer is then the proxy.
+    pub(crate) fn borrow_from(state: &State) -> Self {
+        let headers = HeaderMap::borrow_from(state);
+        let user_agent = headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let ip = match client_ip_header() {
+            Some(name) => forwarded_ip(headers, &name),
+            None => client_addr(state).map(|addr| addr.ip().to_string()),
+        };
+        Self { ip, user_agent }
+    }
+}
+
+/// The last address of the header is the one added by the proxy, the others could be forged.
+fn forwarded_ip(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_string())
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender, OpenapiType, Clone, Debug)]
+pub struct LockoutQueryStringExtractor {
+    email: Option<String>,
+    ip: Option<String>,
+}
+
+/// Failed attempts are counted per email and per client address. Both back off exponentially
+/// and get locked out temporarily after too many failures. With two-factor authentication the
+/// response is a challenge instead, to be completed at `/auth/totp/verify`.
 #[create]
-fn auth(body: CreateToken, conn: &mut PgConnection) -> Result<TokenResponse, Error> {
+async fn auth(state: &mut State, body: CreateToken) -> Result<LoginResponse, Error> {
     body.validate()?;
-    let (uuid, role) = authenticate(&body.email, &body.password, conn)?;
-    let token = generate_jwt(uuid, role)?;
+    let client = Client::borrow_from(state);
+    let repo = Repo::borrow_from(state).clone();
+    let mut response = repo
+        .run(move |mut conn| login(body, client, &mut conn))
+        .await?;
+    if let LoginResponse::Token(tokens) = &mut response {
+        ResponseCookies::start_session(state, tokens);
+    }
+    Ok(response)
+}
+
+fn login(
+    body: CreateToken,
+    client: Client,
+    conn: &mut PgConnection,
+) -> Result<LoginResponse, Error> {
+    let ip = client.ip.clone();
+    login_attempts::check(&body.email, ip.as_deref(), conn)?;
+    let (uuid, role) = match authenticate(&body.email, &body.password, conn) {
+        Ok(found) => found,
+        Err(Error::BadRequest(message)) => {
+            login_attempts::record_failure(&body.email, ip.as_deref(), conn)?;
+            return Err(Error::BadRequest(message));
+        }
+        Err(e) => return Err(e),
+    };
+    login_attempts::clear(Some(&body.email), None, conn)?;
+    challenge_or_tokens(uuid, role, client, conn)
+}
+
+/// Signs in a user whose first factor was verified, unless a second factor is still needed.
+pub(crate) fn challenge_or_tokens(
+    uuid: Uuid,
+    role: Role,
+    client: Client,
+    conn: &mut PgConnection,
+) -> Result<LoginResponse, Error> {
+    let enabled = two_factor::is_enabled(uuid, conn)?;
+    if enabled || (role == Role::Admin && require_admin_2fa()) {
+        let enrolment = match enabled {
+            true => None,
+            false => Some(enrol(uuid, users::select(uuid, conn)?.email(), conn)?),
+        };
+        let challenge = generate_token();
+        login_challenges::insert(uuid, hash_token(&challenge), conn)?;
+        let response = ChallengeResponse {
+            challenge,
+            enrolment,
+        };
+        return Ok(LoginResponse::Challenge(response));
+    }
+    issue_tokens(uuid, role, client, conn).map(LoginResponse::Token)
+}
+
+/// Starts a new session, the refresh token begins a new family.
+pub(crate) fn issue_tokens(
+    uuid: Uuid,
+    role: Role,
+    client: Client,
+    conn: &mut PgConnection,
+) -> Result<TokenResponse, Error> {
+    let family_id = Uuid::new_v4();
+    sessions::insert(
+        Session::new(family_id, uuid, client.ip, client.user_agent),
+        conn,
+    )?;
+    let refresh_token = generate_token();
+    refresh_tokens::insert(uuid, family_id, hash_token(&refresh_token), conn)?;
+    let token = generate_jwt(uuid, role, family_id, conn)?;
     let response = TokenResponse {
         id: uuid,
         token,
+        refresh_token: Some(refresh_token),
         role,
+        recovery_codes: None,
     };
     Ok(response)
 }
 
-pub fn generate_jwt(uuid: Uuid, role: Role) -> Result<String, Error> {
+/// With `COOKIE_AUTH` the refresh token cookie needs the `X-CSRF-Token` header, like other
+/// requests that change state.
+#[endpoint(uri = "refresh", method = "Method::POST", params = false, body = true)]
+async fn refresh(state: &mut State, body: RotateToken) -> Result<TokenResponse, Error> {
+    let previous = match body.refresh_token {
+        Some(refresh_token) => refresh_token,
+        None if CookieAuth::enabled(state) => {
+            let headers = HeaderMap::borrow_from(state);
+            csrf_protected_cookie(headers, Method::borrow_from(state), REFRESH_COOKIE)
+                .map_err(|_| Error::Unauthorized)?
+                .ok_or(Error::Unauthorized)?
+                .to_string()
+        }
+        None => return Err(Error::Unauthorized),
+    };
+    let repo = Repo::borrow_from(state).clone();
+    let mut response = repo
+        .run(move |mut conn| rotate(&previous, &mut conn))
+        .await?;
+    ResponseCookies::start_session(state, &mut response);
+    Ok(response)
+}
+
+fn rotate(previous: &str, conn: &mut PgConnection) -> Result<TokenResponse, Error> {
+    let refresh_token = generate_token();
+    let (uuid, role, family_id) =
+        refresh_tokens::rotate(&hash_token(previous), hash_token(&refresh_token), conn)?;
+    let token = generate_jwt(uuid, role, family_id, conn)?;
+    let response = TokenResponse {
+        id: uuid,
+        token,
+        refresh_token: Some(refresh_token),
+        role,
+        recovery_codes: None,
+    };
+    Ok(response)
+}
+
+/// Not with an API key, which is revoked through its own endpoint instead.
+#[delete_all]
+async fn logout(state: &mut State, auth: AuthenticationStatus) -> Result<NoContent, Error> {
+    let sid = auth.ok().signed_in()?.sid;
+    let repo = Repo::borrow_from(state).clone();
+    repo.run(move |mut conn| {
+        access_tokens::revoke_family(sid, &mut conn)?;
+        refresh_tokens::revoke_family(sid, &mut conn)
+    })
+    .await?;
+    ResponseCookies::end_session(state);
+    Ok(NoContent::default())
+}
+
+/// Always succeeds, so the response does not reveal whether an account exists. A reset is only
+/// kept when its link could be sent.
+#[endpoint(
+    uri = "password/forgot",
+    method = "Method::POST",
+    params = false,
+    body = true
+)]
+fn forgot_password(body: ForgotPassword, conn: &mut PgConnection) -> Result<NoContent, Error> {
+    body.validate()?;
+    if let Some(user) = select_by_email(&body.email, conn)? {
+        let sent = conn.transaction::<_, Error, _>(|conn| {
+            let token = generate_token();
+            password_resets::insert(*user.id(), hash_token(&token), conn)?;
+            let link = format!("{}/reset-password?token={token}", app_url());
+            mailer()?.send(
+                user.email(),
+                "Reset your password",
+                &format!("Follow this link to choose a new password: {link}"),
+            )
+        });
+        if let Err(e) = sent {
+            error!("Password reset of {}: {e:?}", user.id());
+        }
+    }
+    Ok(NoContent::default())
+}
+
+#[endpoint(
+    uri = "password/reset",
+    method = "Method::POST",
+    params = false,
+    body = true
+)]
+fn reset_password(body: RedeemPasswordReset, conn: &mut PgConnection) -> Result<NoContent, Error> {
+    body.validate()?;
+    password_resets::redeem(&hash_token(&body.token), &body.password, conn)
+        .map(|_| NoContent::default())
+}
+
+/// Requires the `users:write` scope.
+#[endpoint(
+    uri = "lockout",
+    method = "Method::DELETE",
+    params = true,
+    body = false
+)]
+fn clear_lockout(
+    auth: AuthenticationStatus,
+    query: LockoutQueryStringExtractor,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    auth.ok().scope(Scope::UsersWrite)?;
+    login_attempts::clear(query.email.as_deref(), query.ip.as_deref(), conn)
+        .map(|_| NoContent::default())
+}
+
+pub fn generate_jwt(
+    uuid: Uuid,
+    role: Role,
+    family_id: Uuid,
+    conn: &mut PgConnection,
+) -> Result<String, Error> {
+    let expires_at = Utc::now() + Duration::minutes(ACCESS_TOKEN_MINUTES);
+    let jti = access_tokens::insert(uuid, family_id, expires_at.naive_utc(), conn)?;
     // https://datatracker.ietf.org/doc/html/rfc7519#section-4.1
     let auth_data = AuthData {
         sub: uuid,
         role,
-        exp: (Utc::now().timestamp() + 30 * 60) as u64,
+        scope: Scope::granted_to(role),
+        jti,
+        sid: family_id,
+        exp: expires_at.timestamp() as u64,
+        api_key: false,
     };
-    encode(
-        &Header::default(),
-        &auth_data,
-        &EncodingKey::from_secret(jwt_secret().as_bytes()),
-    )
-    .map_err(Error::from)
+    keyring().encode(&auth_data)
 }
 
 #[cfg(test)]
@@ -112,13 +587,17 @@ mod tests {
     use super::*;
     use crate::db::migrations::run_migrations_and_test_transactions;
     use crate::db::users::insert;
+    use crate::middleware::CSRF_HEADER;
     use crate::models::{Password, User};
-    use crate::router::router;
+    use crate::router::{router, router_with};
+    use crate::schema::password_resets;
+    use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
     use fake::{Fake, Faker};
+    use gotham::hyper::header::{AUTHORIZATION, COOKIE, SET_COOKIE};
     use gotham::mime::APPLICATION_JSON;
     use gotham::plain::test::AsyncTestServer;
     use gotham_restful::gotham::hyper::StatusCode;
-    use jsonwebtoken::{decode, DecodingKey, Validation};
+    use serde_json::json;
     use tokio::sync::oneshot;
 
     impl AuthData {
@@ -126,18 +605,27 @@ mod tests {
             Self {
                 sub: Uuid::new_v4(),
                 role,
+                scope: Scope::granted_to(role),
+                jti: Uuid::new_v4(),
+                sid: Uuid::new_v4(),
                 exp: 0,
+                api_key: false,
             }
         }
     }
 
     #[test]
-    fn is_admin() {
+    fn scopes_follow_role() {
         let auth = AuthData::test(Role::Admin);
-        assert!(auth.is_admin().is_ok());
+        assert!(auth.has_scope(Scope::UsersWrite).is_ok());
+
+        let auth = AuthData::test(Role::Editor);
+        assert!(auth.has_scope(Scope::SnippetsWrite).is_ok());
+        assert!(auth.has_scope(Scope::UsersRead).is_err());
 
         let auth = AuthData::test(Role::User);
-        assert!(auth.is_admin().is_err());
+        assert!(auth.has_scope(Scope::SnippetsRead).is_ok());
+        assert!(auth.has_scope(Scope::TermsWrite).is_err());
     }
 
     #[test]
@@ -152,13 +640,32 @@ mod tests {
     }
 
     #[test]
-    fn admin_permission() {
+    fn scope_permission() {
         let auth = AuthData::test(Role::Admin);
         let sub = auth.sub;
-        assert_eq!(Ok(auth).admin().unwrap(), sub);
+        assert_eq!(Ok(auth).scope(Scope::UsersRead).unwrap(), sub);
+
+        let auth = AuthData::test(Role::Editor);
+        let sub = auth.sub;
+        assert_eq!(Ok(auth).scope(Scope::TermsWrite).unwrap(), sub);
+
+        let auth = AuthData::test(Role::Editor);
+        assert!(Ok(auth).scope(Scope::UsersWrite).is_err());
 
         let auth = AuthData::test(Role::User);
-        assert!(Ok(auth).admin().is_err());
+        assert!(Ok(auth).scope(Scope::SnippetsWrite).is_err());
+    }
+
+    #[test]
+    fn scope_claim_is_space_separated() {
+        let auth = AuthData::test(Role::Editor);
+        let claims = serde_json::to_value(&auth).expect("Can not encode");
+        assert_eq!(
+            claims["scope"],
+            "snippets:read terms:read authors:read snippets:write terms:write"
+        );
+        let decoded: AuthData = serde_json::from_value(claims).expect("Can not decode");
+        assert_eq!(decoded.scope, auth.scope);
     }
 
     #[test]
@@ -175,6 +682,51 @@ mod tests {
         assert!(Ok(auth).user(Uuid::new_v4()).is_err());
     }
 
+    #[test]
+    fn user_write_permission() {
+        let auth = AuthData::test(Role::Admin);
+        let sub = auth.sub;
+        assert_eq!(Ok(auth).user_write(Uuid::new_v4()).unwrap(), sub);
+
+        let mut auth = AuthData::test(Role::Admin);
+        auth.scope = vec![Scope::UsersRead];
+        let sub = auth.sub;
+        assert_eq!(Ok(auth.clone()).user_write(sub).unwrap(), sub);
+        assert!(Ok(auth.clone()).user_write(Uuid::new_v4()).is_err());
+
+        auth.api_key = true;
+        assert!(Ok(auth).user_write(sub).is_err());
+    }
+
+    #[test]
+    fn owner_permission() {
+        let auth = AuthData::test(Role::User);
+        let sub = auth.sub;
+        assert_eq!(Ok(auth).owner(sub).unwrap().sub, sub);
+
+        let auth = AuthData::test(Role::Admin);
+        assert!(Ok(auth).owner(Uuid::new_v4()).is_err());
+
+        let mut auth = AuthData::test(Role::User);
+        auth.api_key = true;
+        let sub = auth.sub;
+        assert!(Ok(auth.clone()).owner(sub).is_err());
+        assert!(Ok(auth).signed_in().is_err());
+    }
+
+    #[test]
+    fn client_ip_is_taken_from_the_proxy() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1, 203.0.113.7".parse().unwrap());
+        headers.insert("fly-client-ip", "not an address".parse().unwrap());
+        assert_eq!(
+            forwarded_ip(&headers, "X-Forwarded-For"),
+            Some("203.0.113.7".to_string())
+        );
+        assert_eq!(forwarded_ip(&headers, "Fly-Client-IP"), None);
+        assert_eq!(forwarded_ip(&headers, "X-Real-IP"), None);
+    }
+
     #[tokio::test]
     async fn valid_request() {
         let user = User::fake(Role::User);
@@ -212,15 +764,287 @@ mod tests {
             .expect("Can not get response body");
         let response: TokenResponse =
             serde_json::from_slice(&body).expect("Can not to convert bytes to string");
-        let mut validation = Validation::default();
-        validation.sub = Some(user_id.to_string());
-        let result = decode::<AuthData>(
-            &response.token,
-            &DecodingKey::from_secret(jwt_secret().as_bytes()),
-            &validation,
+        let claims = keyring()
+            .decode::<AuthData>(&response.token)
+            .expect("Can not decode JWT");
+        assert_eq!(claims.sub, user_id);
+        assert_eq!(claims.role, user_role);
+        assert_eq!(claims.scope, Scope::granted_to(user_role));
+    }
+
+    #[tokio::test]
+    async fn admin_must_enrol_when_required() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::Admin);
+            let password: Password = Faker.fake();
+            let request = CreateToken {
+                email: user.email().clone(),
+                password: password.password().clone(),
+            };
+            insert(user, password.password(), conn).expect("Can not create");
+            let response = temp_env::with_var("REQUIRE_ADMIN_2FA", Some("true"), || {
+                login(request, Client::default(), conn)
+            });
+            match response.expect("Can not sign in") {
+                LoginResponse::Challenge(ChallengeResponse {
+                    enrolment: Some(_), ..
+                }) => (),
+                response => panic!("Expected an enrolment, got {response:?}"),
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn unsent_reset_is_not_revealed() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::User);
+            let password: Password = Faker.fake();
+            let body = ForgotPassword {
+                email: user.email().clone(),
+            };
+            let user_id = insert(user, password.password(), conn).expect("Can not create");
+            let result = temp_env::with_var("SMTP_URL", Some("smtp://localhost:1"), || {
+                forgot_password(body, conn)
+            });
+            assert!(result.is_ok());
+            let resets = password_resets::dsl::password_resets
+                .filter(password_resets::dsl::user_id.eq(user_id))
+                .count()
+                .get_result::<i64>(conn);
+            assert_eq!(resets, Ok(0));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn failed_login_is_throttled() {
+        let user = User::fake(Role::User);
+        let password: Password = Faker.fake();
+        let user_email = user.email().clone();
+        let user_password = password.password().clone();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            insert(user, password.password(), conn).expect("Can not create");
+        })
+        .await;
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+        for (password, status) in [
+            ("wrong password".to_string(), StatusCode::BAD_REQUEST),
+            (user_password, StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let request = CreateToken {
+                email: user_email.clone(),
+                password,
+            };
+            let body = serde_json::to_string(&request).expect("Can not encode JSON");
+            let response = test_server
+                .client()
+                .post("http://localhost:3000/api/v1/auth")
+                .body(body)
+                .mime(APPLICATION_JSON)
+                .perform()
+                .await
+                .expect("Can not send a request");
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_request() {
+        let user = User::fake(Role::User);
+        let password: Password = Faker.fake();
+        let user_email = user.email().clone();
+        let user_password = password.password().clone();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            insert(user, password.password(), conn).expect("Can not create");
+        })
+        .await;
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+        let request = CreateToken {
+            email: user_email,
+            password: user_password,
+        };
+        let body = serde_json::to_string(&request).expect("Can not encode JSON");
+        let response = test_server
+            .client()
+            .post("http://localhost:3000/api/v1/auth")
+            .body(body)
+            .mime(APPLICATION_JSON)
+            .perform()
+            .await
+            .expect("Can not send a request");
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let signed_in: TokenResponse =
+            serde_json::from_slice(&body).expect("Can not to convert bytes to string");
+
+        let request = RotateToken {
+            refresh_token: signed_in.refresh_token.clone(),
+        };
+        let body = serde_json::to_string(&request).expect("Can not encode JSON");
+        let response = test_server
+            .client()
+            .post("http://localhost:3000/api/v1/auth/refresh")
+            .body(body.clone())
+            .mime(APPLICATION_JSON)
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let refreshed: TokenResponse = serde_json::from_slice(
+            &response
+                .read_body()
+                .await
+                .expect("Can not get response body"),
         )
-        .expect("Can not decode JWT");
-        assert_eq!(result.claims.sub, user_id);
-        assert_eq!(result.claims.role, user_role);
+        .expect("Can not to convert bytes to string");
+        assert_eq!(refreshed.id, signed_in.id);
+        assert_ne!(refreshed.refresh_token, signed_in.refresh_token);
+
+        let response = test_server
+            .client()
+            .post("http://localhost:3000/api/v1/auth/refresh")
+            .body(body)
+            .mime(APPLICATION_JSON)
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn cookie_session_needs_csrf_token() {
+        let user = User::fake(Role::User);
+        let password: Password = Faker.fake();
+        let body = json!({"email": user.email(), "password": password.password()}).to_string();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            insert(user, password.password(), conn).expect("Can not create");
+        })
+        .await;
+        let test_server = AsyncTestServer::new(router_with(repo, true))
+            .await
+            .expect("Can not start test server");
+        let response = test_server
+            .client()
+            .post("http://localhost:3000/api/v1/auth")
+            .body(body)
+            .mime(APPLICATION_JSON)
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookies = response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok()?.split(';').next())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(cookies.len(), 3);
+        let csrf = cookies
+            .iter()
+            .find_map(|cookie| cookie.strip_prefix("csrf_token="))
+            .expect("Can not find CSRF token")
+            .to_string();
+        let cookies = cookies.join("; ");
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let signed_in: TokenResponse = serde_json::from_slice(&body).expect("Can not decode");
+        assert_eq!(signed_in.refresh_token, None);
+
+        for (path, body) in [
+            ("auth/refresh", "{}"),
+            ("keys", r#"{"name": "Cookie key"}"#),
+        ] {
+            let post = |csrf: Option<String>| {
+                let client = test_server.client();
+                let cookies = cookies.clone();
+                async move {
+                    let mut request = client
+                        .post(format!("http://localhost:3000/api/v1/{path}"))
+                        .body(body)
+                        .mime(APPLICATION_JSON)
+                        .header(COOKIE, cookies);
+                    if let Some(csrf) = csrf {
+                        request = request.header(CSRF_HEADER, csrf);
+                    }
+                    request.perform().await.expect("Can not send a request")
+                }
+            };
+            let response = post(None).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let response = post(Some(csrf.clone())).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_token() {
+        let user = User::fake(Role::User);
+        let password: Password = Faker.fake();
+        let user_email = user.email().clone();
+        let user_password = password.password().clone();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            insert(user, password.password(), conn).expect("Can not create");
+        })
+        .await;
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+        let request = CreateToken {
+            email: user_email,
+            password: user_password,
+        };
+        let body = serde_json::to_string(&request).expect("Can not encode JSON");
+        let response = test_server
+            .client()
+            .post("http://localhost:3000/api/v1/auth")
+            .body(body)
+            .mime(APPLICATION_JSON)
+            .perform()
+            .await
+            .expect("Can not send a request");
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let signed_in: TokenResponse =
+            serde_json::from_slice(&body).expect("Can not to convert bytes to string");
+        let bearer = format!("Bearer {}", signed_in.token);
+
+        let response = test_server
+            .client()
+            .get("http://localhost:3000/api/v1/authors")
+            .header(AUTHORIZATION, bearer.clone())
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = test_server
+            .client()
+            .delete("http://localhost:3000/api/v1/auth")
+            .header(AUTHORIZATION, bearer.clone())
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = test_server
+            .client()
+            .get("http://localhost:3000/api/v1/authors")
+            .header(AUTHORIZATION, bearer)
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
     }
 }
@@ -1,7 +1,7 @@
 use crate::db::authors;
 use crate::error::Error;
 use crate::models::Author;
-use crate::resources::auth::AuthenticationStatus;
+use crate::resources::auth::{AuthenticationStatus, Permission, Scope};
 
 use diesel::PgConnection;
 use gotham_restful::*;
@@ -26,12 +26,13 @@ impl From<Author> for AuthorResponse {
     }
 }
 
+/// Requires the `authors:read` scope.
 #[read_all]
 fn read_all(
     auth: AuthenticationStatus,
     conn: &mut PgConnection,
 ) -> Result<Vec<AuthorResponse>, Error> {
-    auth.ok()?;
+    auth.ok().scope(Scope::AuthorsRead)?;
     let authors = authors::select_all(conn)?;
     let results = authors.into_iter().map(AuthorResponse::from).collect();
     Ok(results)
@@ -0,0 +1,317 @@
+use crate::db::exports;
+use crate::error::Error;
+use crate::models::{
+    enums::Role, ApiKey, Invitation, LoginAttempt, PasswordReset, Session, User, UserIdentity,
+};
+use crate::resources::auth::{AuthenticationStatus, Permission};
+use crate::resources::user::UserPath;
+
+use chrono::NaiveDateTime;
+use diesel::PgConnection;
+use gotham_restful::gotham::hyper::Method;
+use gotham_restful::*;
+use openapi_type::OpenapiType;
+use serde_derive::Serialize;
+use uuid::Uuid;
+
+#[derive(Resource)]
+#[resource(export)]
+pub struct Resource;
+
+/// Password hashes, token hashes and two-factor secrets are left out.
+#[derive(Serialize, OpenapiType)]
+struct ExportResponse {
+    profile: ProfileExport,
+    /// Every sign in, including the ones that ended.
+    sessions: Vec<SessionExport>,
+    identities: Vec<IdentityExport>,
+    api_keys: Vec<ApiKeyExport>,
+    /// Invitations the user sent.
+    invitations: Vec<InvitationExport>,
+    two_factor_enabled: bool,
+    /// Unused recovery codes, the codes themselves are only shown once.
+    recovery_codes_left: i64,
+    password_resets: Vec<PasswordResetExport>,
+    /// Failed sign ins with the email since the last successful one.
+    login_attempt: Option<LoginAttemptExport>,
+}
+
+#[derive(Serialize, OpenapiType)]
+struct ProfileExport {
+    id: Uuid,
+    name: String,
+    email: String,
+    role: Role,
+    is_deleted: bool,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+}
+
+#[derive(Serialize, OpenapiType)]
+struct SessionExport {
+    id: Uuid,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, OpenapiType)]
+struct IdentityExport {
+    issuer: String,
+    subject: String,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, OpenapiType)]
+struct ApiKeyExport {
+    id: Uuid,
+    name: String,
+    prefix: String,
+    role: Role,
+    scopes: Vec<String>,
+    expires_at: Option<NaiveDateTime>,
+    last_used_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, OpenapiType)]
+struct InvitationExport {
+    id: Uuid,
+    email: String,
+    role: Role,
+    expires_at: NaiveDateTime,
+    accepted_at: Option<NaiveDateTime>,
+    revoked_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, OpenapiType)]
+struct PasswordResetExport {
+    id: Uuid,
+    expires_at: NaiveDateTime,
+    used_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, OpenapiType)]
+struct LoginAttemptExport {
+    failures: i32,
+    last_failed_at: NaiveDateTime,
+    locked_until: Option<NaiveDateTime>,
+}
+
+impl From<User> for ProfileExport {
+    fn from(user: User) -> Self {
+        let (id, name, email, role, is_deleted, created_at, updated_at) = user.dissolve();
+        Self {
+            id,
+            name,
+            email,
+            role,
+            is_deleted,
+            created_at,
+            updated_at,
+        }
+    }
+}
+
+impl From<Session> for SessionExport {
+    fn from(session: Session) -> Self {
+        let (id, _, ip, user_agent, created_at) = session.dissolve();
+        Self {
+            id,
+            ip,
+            user_agent,
+            created_at,
+        }
+    }
+}
+
+impl From<UserIdentity> for IdentityExport {
+    fn from(identity: UserIdentity) -> Self {
+        let (issuer, subject, _, created_at) = identity.dissolve();
+        Self {
+            issuer,
+            subject,
+            created_at,
+        }
+    }
+}
+
+impl From<ApiKey> for ApiKeyExport {
+    fn from(key: ApiKey) -> Self {
+        let (
+            id,
+            _,
+            name,
+            prefix,
+            _,
+            role,
+            expires_at,
+            last_used_at,
+            revoked_at,
+            created_at,
+            scopes,
+        ) = key.dissolve();
+        Self {
+            id,
+            name,
+            prefix,
+            role,
+            scopes,
+            expires_at,
+            last_used_at,
+            revoked_at,
+            created_at,
+        }
+    }
+}
+
+impl From<Invitation> for InvitationExport {
+    fn from(invitation: Invitation) -> Self {
+        let (id, email, role, _, _, expires_at, accepted_at, revoked_at, created_at) =
+            invitation.dissolve();
+        Self {
+            id,
+            email,
+            role,
+            expires_at,
+            accepted_at,
+            revoked_at,
+            created_at,
+        }
+    }
+}
+
+impl From<PasswordReset> for PasswordResetExport {
+    fn from(reset: PasswordReset) -> Self {
+        let (id, _, _, expires_at, used_at, created_at) = reset.dissolve();
+        Self {
+            id,
+            expires_at,
+            used_at,
+            created_at,
+        }
+    }
+}
+
+impl From<LoginAttempt> for LoginAttemptExport {
+    fn from(attempt: LoginAttempt) -> Self {
+        let (_, _, failures, last_failed_at, locked_until) = attempt.dissolve();
+        Self {
+            failures,
+            last_failed_at,
+            locked_until,
+        }
+    }
+}
+
+/// Everything stored about the user, for privacy requests. Requires the `users:read` scope,
+/// except for the own account.
+#[endpoint(
+    uri = ":id/export",
+    method = "Method::GET",
+    params = false,
+    body = false
+)]
+fn export(
+    auth: AuthenticationStatus,
+    path: UserPath,
+    conn: &mut PgConnection,
+) -> Result<ExportResponse, Error> {
+    auth.ok().user(path.id)?;
+    let (
+        user,
+        sessions,
+        identities,
+        api_keys,
+        invitations,
+        totp_secret,
+        recovery_codes_left,
+        password_resets,
+        login_attempt,
+    ) = exports::select(path.id, conn)?.dissolve();
+    Ok(ExportResponse {
+        profile: user.into(),
+        sessions: sessions.into_iter().map(SessionExport::from).collect(),
+        identities: identities.into_iter().map(IdentityExport::from).collect(),
+        api_keys: api_keys.into_iter().map(ApiKeyExport::from).collect(),
+        invitations: invitations
+            .into_iter()
+            .map(InvitationExport::from)
+            .collect(),
+        two_factor_enabled: totp_secret.is_some_and(|secret| secret.confirmed_at().is_some()),
+        recovery_codes_left,
+        password_resets: password_resets
+            .into_iter()
+            .map(PasswordResetExport::from)
+            .collect(),
+        login_attempt: login_attempt.map(LoginAttemptExport::from),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::users;
+    use crate::models::{enums::Role, Password, User};
+    use crate::resources::auth::{issue_tokens, Client};
+    use crate::router::router;
+    use fake::{Fake, Faker};
+    use gotham::hyper::header::AUTHORIZATION;
+    use gotham::plain::test::AsyncTestServer;
+    use gotham_restful::gotham::hyper::StatusCode;
+    use serde_json::Value;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn only_own_data_is_exported() {
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let [user_id, other_id] = [(); 2].map(|_| {
+                let password: Password = Faker.fake();
+                users::insert(User::fake(Role::User), password.password(), conn)
+                    .expect("Can not create")
+            });
+            let response = issue_tokens(user_id, Role::User, Client::default(), conn)
+                .expect("Can not sign in");
+            let response = serde_json::to_value(response).expect("Can not encode JSON");
+            let token = response["token"].as_str().expect("Can not find token");
+            tx.send((user_id, other_id, token.to_string()))
+                .expect("Can not send");
+        })
+        .await;
+        let (user_id, other_id, token) = rx.await.expect("Can not find token");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+        let export = |id| {
+            let client = test_server.client();
+            let token = token.clone();
+            async move {
+                client
+                    .get(format!("http://localhost:3000/api/v1/users/{id}/export"))
+                    .header(AUTHORIZATION, format!("Bearer {token}"))
+                    .perform()
+                    .await
+                    .expect("Can not send a request")
+            }
+        };
+
+        let response = export(other_id).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = export(user_id).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let export: Value = serde_json::from_slice(&body).expect("Can not decode JSON");
+        assert_eq!(export["profile"]["id"], user_id.to_string());
+        assert_eq!(export["sessions"].as_array().map(Vec::len), Some(1));
+        assert_eq!(export["recovery_codes_left"], 0);
+        assert_eq!(export["login_attempt"], Value::Null);
+    }
+}
@@ -63,6 +63,7 @@ pub struct Forms {
     pub password: String,
     pub role: String,
     pub role_admin: String,
+    pub role_editor: String,
     pub role_user: String,
     pub related: String,
     pub text: String,
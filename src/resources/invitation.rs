@@ -0,0 +1,171 @@
+use crate::authentication::{generate_token, hash_token};
+use crate::config::app_url;
+use crate::db::invitations;
+use crate::error::Error;
+use crate::mailer::mailer;
+use crate::models::{enums::Role, Invitation};
+use crate::resources::auth::{AuthenticationStatus, Permission, Scope};
+use crate::resources::validation::users::*;
+
+use chrono::NaiveDateTime;
+use diesel::{Connection, PgConnection};
+use gotham_restful::gotham::hyper::Method;
+use gotham_restful::*;
+use openapi_type::OpenapiType;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Resource)]
+#[resource(create, read_all, delete, accept)]
+pub struct Resource;
+
+#[derive(Serialize, Deserialize, OpenapiType, Validate)]
+pub struct CreateInvitation {
+    #[validate(email, length(min = "MIN_EMAIL_LENGTH"))]
+    pub email: String,
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, OpenapiType, Validate)]
+pub struct AcceptInvitation {
+    pub token: String,
+    #[validate(length(min = "MIN_NAME_LENGTH"))]
+    pub name: String,
+    #[validate(length(min = "MIN_PASSWORD_LENGTH"))]
+    pub password: String,
+}
+
+#[derive(Serialize, OpenapiType)]
+struct InvitationResponse {
+    id: Uuid,
+    email: String,
+    role: Role,
+    expires_at: NaiveDateTime,
+    created_at: NaiveDateTime,
+}
+
+impl From<Invitation> for InvitationResponse {
+    fn from(invitation: Invitation) -> Self {
+        let (id, email, role, _, _, expires_at, _, _, created_at) = invitation.dissolve();
+        Self {
+            id,
+            email,
+            role,
+            expires_at,
+            created_at,
+        }
+    }
+}
+
+/// Requires the `users:write` scope. The invitee receives a single-use link by email.
+#[create]
+fn create(
+    auth: AuthenticationStatus,
+    mut body: CreateInvitation,
+    conn: &mut PgConnection,
+) -> Result<Uuid, Error> {
+    let invited_by = auth.ok().scope(Scope::UsersWrite)?;
+    body.email = normalize_email(&body.email);
+    body.validate()?;
+    // An invitation that could not be sent would block inviting the same email again
+    conn.transaction::<_, Error, _>(|conn| {
+        let token = generate_token();
+        let id = invitations::insert(
+            body.email.clone(),
+            body.role,
+            hash_token(&token),
+            invited_by,
+            conn,
+        )?;
+        let link = format!("{}/accept-invitation?token={token}", app_url());
+        mailer()?.send(
+            &body.email,
+            "You are invited",
+            &format!("Follow this link to create your account: {link}"),
+        )?;
+        Ok(id)
+    })
+}
+
+/// Requires the `users:read` scope. Lists the invitations that can still be accepted.
+#[read_all]
+fn read_all(
+    auth: AuthenticationStatus,
+    conn: &mut PgConnection,
+) -> Result<Vec<InvitationResponse>, Error> {
+    auth.ok().scope(Scope::UsersRead)?;
+    let result = invitations::select_pending(conn)?;
+    Ok(result.into_iter().map(InvitationResponse::from).collect())
+}
+
+/// Requires the `users:write` scope.
+#[delete]
+fn delete(
+    auth: AuthenticationStatus,
+    id: Uuid,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    auth.ok().scope(Scope::UsersWrite)?;
+    invitations::revoke(id, conn).map(|_| NoContent::default())
+}
+
+/// Creates the account of the invitee with the role chosen by the inviting admin.
+#[endpoint(uri = "accept", method = "Method::POST", params = false, body = true)]
+fn accept(body: AcceptInvitation, conn: &mut PgConnection) -> Result<Uuid, Error> {
+    body.validate()?;
+    invitations::accept(&hash_token(&body.token), body.name, &body.password, conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::users::insert;
+    use crate::models::{Password, User};
+    use crate::resources::auth::generate_jwt;
+    use crate::router::router;
+    use fake::{Fake, Faker};
+    use gotham::hyper::header::AUTHORIZATION;
+    use gotham::mime::APPLICATION_JSON;
+    use gotham::plain::test::AsyncTestServer;
+    use gotham_restful::gotham::hyper::StatusCode;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn only_admins_invite() {
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let tokens = [Role::Editor, Role::Admin].map(|role| {
+                let user = User::fake(role);
+                let password: Password = Faker.fake();
+                let user_id = insert(user, password.password(), conn).expect("Can not create");
+                generate_jwt(user_id, role, Uuid::new_v4(), conn).expect("Can not sign")
+            });
+            tx.send(tokens).expect("Can not send");
+        })
+        .await;
+        let [editor, admin] = rx.await.expect("Can not find tokens");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+
+        for (token, status) in [(editor, StatusCode::FORBIDDEN), (admin, StatusCode::OK)] {
+            let request = CreateInvitation {
+                email: User::fake(Role::User).email().clone(),
+                role: Role::User,
+            };
+            let body = serde_json::to_string(&request).expect("Can not encode JSON");
+            let response = test_server
+                .client()
+                .post("http://localhost:3000/api/v1/invitations")
+                .body(body)
+                .mime(APPLICATION_JSON)
+                .header(AUTHORIZATION, format!("Bearer {token}"))
+                .perform()
+                .await
+                .expect("Can not send a request");
+            assert_eq!(response.status(), status);
+        }
+    }
+}
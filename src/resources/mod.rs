@@ -1,8 +1,13 @@
+pub mod api_key;
 pub mod auth;
 pub mod author;
+pub mod export;
 pub mod i18n;
+pub mod invitation;
+pub mod oidc;
 pub mod snippet;
 pub mod term;
 pub mod translation;
+pub mod two_factor;
 pub mod user;
 pub mod validation;
@@ -0,0 +1,215 @@
+use crate::authentication::hash_token;
+use crate::config::oidc_provision_role;
+use crate::db::oidc;
+use crate::error::Error;
+use crate::middleware::ResponseCookies;
+use crate::models::enums::Role;
+use crate::oidc::Provider;
+use crate::resources::auth::{challenge_or_tokens, Client, LoginResponse};
+use crate::router::Repo;
+
+use gotham_restful::gotham::hyper::Method;
+use gotham_restful::gotham::state::{FromState, State};
+use gotham_restful::*;
+use openapi_type::OpenapiType;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Resource)]
+#[resource(start_oidc, complete_oidc)]
+pub struct Resource;
+
+#[derive(Deserialize, Serialize, OpenapiType)]
+struct OidcAuthorization {
+    /// Where to send the browser to sign in at the provider.
+    url: String,
+}
+
+#[derive(Deserialize, Serialize, OpenapiType, Clone)]
+struct OidcCallback {
+    code: String,
+    state: String,
+}
+
+/// Starts a sign in at the configured OpenID Connect provider.
+#[endpoint(uri = "oidc", method = "Method::GET", params = false, body = false)]
+async fn start_oidc(state: &mut State) -> Result<OidcAuthorization, Error> {
+    let provider = Provider::from_config().ok_or(not_configured())?;
+    let repo = Repo::borrow_from(state).clone();
+    start(&provider, &repo).await
+}
+
+/// Completes a sign in with the `code` and `state` the provider redirected back with. Accounts
+/// are matched by the subject, or else by a verified email, and are created when
+/// `OIDC_PROVISION_ROLE` is set. A second factor is asked for like at `/auth`.
+#[endpoint(
+    uri = "oidc/callback",
+    method = "Method::POST",
+    params = false,
+    body = true
+)]
+async fn complete_oidc(state: &mut State, body: OidcCallback) -> Result<LoginResponse, Error> {
+    let provider = Provider::from_config().ok_or(not_configured())?;
+    let client = Client::borrow_from(state);
+    let repo = Repo::borrow_from(state).clone();
+    let mut response = complete(&provider, oidc_provision_role(), body, client, &repo).await?;
+    if let LoginResponse::Token(tokens) = &mut response {
+        ResponseCookies::start_session(state, tokens);
+    }
+    Ok(response)
+}
+
+async fn start(provider: &Provider, repo: &Repo) -> Result<OidcAuthorization, Error> {
+    let discovery = provider.discover().await?;
+    let authorization = provider.authorize(&discovery)?;
+    let state_hash = hash_token(&authorization.state);
+    repo.run(move |mut conn| {
+        oidc::insert_login(
+            state_hash,
+            authorization.nonce,
+            authorization.code_verifier,
+            &mut conn,
+        )
+    })
+    .await?;
+    Ok(OidcAuthorization {
+        url: authorization.url,
+    })
+}
+
+async fn complete(
+    provider: &Provider,
+    provision_role: Option<Role>,
+    body: OidcCallback,
+    client: Client,
+    repo: &Repo,
+) -> Result<LoginResponse, Error> {
+    let state_hash = hash_token(&body.state);
+    let login = repo
+        .run(move |mut conn| oidc::take_login(&state_hash, &mut conn))
+        .await?;
+    let discovery = provider.discover().await?;
+    let claims = provider
+        .exchange(&discovery, &body.code, login.code_verifier(), login.nonce())
+        .await?;
+    repo.run(move |mut conn| {
+        let (user_id, role) = oidc::sign_in(&claims, provision_role, &mut conn)?;
+        challenge_or_tokens(user_id, role, client, &mut conn)
+    })
+    .await
+}
+
+fn not_configured() -> Error {
+    Error::BadRequest("Not configured".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::{two_factor, users};
+    use crate::models::{Password, User};
+    use crate::oidc::mock;
+    use crate::totp;
+    use chrono::Utc;
+    use fake::{Fake, Faker};
+    use std::collections::HashMap;
+    use url::Url;
+
+    /// Starts a sign in at the mock issuer, which will answer for `sub` and `email`.
+    async fn started(
+        issuer: &mock::MockIssuer,
+        provider: &Provider,
+        repo: &Repo,
+        sub: &str,
+        email: &str,
+    ) -> OidcCallback {
+        let authorization = start(provider, repo).await.expect("Can not start");
+        let query = Url::parse(&authorization.url)
+            .expect("Can not parse")
+            .query_pairs()
+            .into_owned()
+            .collect::<HashMap<_, _>>();
+        let mut pending = issuer.pending.lock().expect("Can not lock");
+        pending.nonce = query["nonce"].clone();
+        pending.code_challenge = query["code_challenge"].clone();
+        pending.sub = sub.to_string();
+        pending.email = email.to_string();
+        OidcCallback {
+            code: mock::CODE.to_string(),
+            state: query["state"].clone(),
+        }
+    }
+
+    fn provider(issuer: &mock::MockIssuer) -> Provider {
+        Provider::new(
+            issuer.url.clone(),
+            mock::CLIENT_ID.to_string(),
+            None,
+            "openid email".to_string(),
+            "http://localhost:3000/oidc/callback".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn sign_in_provisions_an_account() {
+        let issuer = mock::start().await;
+        let provider = provider(&issuer);
+        let repo = run_migrations_and_test_transactions(|_| {}).await;
+        let callback = started(
+            &issuer,
+            &provider,
+            &repo,
+            "provisioned",
+            "provisioned@example.com",
+        )
+        .await;
+
+        let response = complete(
+            &provider,
+            Some(Role::User),
+            callback.clone(),
+            Client::default(),
+            &repo,
+        )
+        .await
+        .expect("Can not sign in");
+        let response = serde_json::to_value(response).expect("Can not encode JSON");
+        assert_eq!(response["role"], "User");
+
+        let replayed = complete(
+            &provider,
+            Some(Role::User),
+            callback.clone(),
+            Client::default(),
+            &repo,
+        )
+        .await;
+        assert_eq!(replayed.map(|_| ()), Err(Error::Unauthorized));
+    }
+
+    #[tokio::test]
+    async fn second_factor_is_still_needed() {
+        let issuer = mock::start().await;
+        let provider = provider(&issuer);
+        let email = "second-factor@example.com";
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let user = User::new("Second factor".to_string(), email.to_string(), Role::Admin);
+            let password: Password = Faker.fake();
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+            let secret = totp::generate_secret();
+            two_factor::enrol(user_id, &secret, conn).expect("Can not enrol");
+            let code = totp::code(&secret, Utc::now().timestamp());
+            two_factor::verify(user_id, &code, conn).expect("Can not confirm");
+        })
+        .await;
+        let callback = started(&issuer, &provider, &repo, "second factor", email).await;
+
+        let response = complete(&provider, None, callback, Client::default(), &repo)
+            .await
+            .expect("Can not sign in");
+        match response {
+            LoginResponse::Challenge(challenge) => assert!(challenge.enrolment.is_none()),
+            LoginResponse::Token(_) => panic!("Signed in without a second factor"),
+        }
+    }
+}
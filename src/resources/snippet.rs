@@ -1,30 +1,133 @@
 use crate::db::snippets;
 use crate::error::Error;
 use crate::models::Snippet;
-use crate::resources::auth::{AuthenticationStatus, Permission};
+use crate::resources::auth::{AuthenticationStatus, Permission, Scope};
 
-use crate::db::snippets::{count, SnippetWithRelated};
+use crate::db::snippets::{count, Position, SnippetFilter, SnippetWithRelated, Sort, SortKey};
 use crate::models::enums::Media;
 use crate::resources::validation::snippets::MIN_TEXT_LENGTH;
+use crate::router::{Repo, API_URL};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::NaiveDateTime;
 use diesel::PgConnection;
 use gotham_derive::{StateData, StaticResponseExtender};
-use gotham_restful::gotham::hyper::Method;
+use gotham_restful::gotham::hyper::{Method, Uri};
+use gotham_restful::gotham::state::{FromState, State};
 use gotham_restful::*;
 use openapi_type::OpenapiType;
 use serde_derive::{Deserialize, Serialize};
+use url::form_urlencoded;
 use uuid::Uuid;
 use validator::Validate;
 
 pub const PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
 
 #[derive(Resource)]
 #[resource(create, read_all, search, update, delete)]
 pub struct Resource;
 
-#[derive(Deserialize, StateData, StaticResponseExtender, OpenapiType, Clone, Debug)]
+/// Filters that can be repeated match any of their values, all filters have to match.
+#[derive(Deserialize, StateData, StaticResponseExtender, OpenapiType, Validate, Clone, Debug)]
 pub struct SnippetQueryStringExtractor {
-    term_id: Option<Uuid>,
-    page: i64,
+    #[serde(default)]
+    term_id: Vec<Uuid>,
+    /// Whether snippets need `any` (the default) or `all` of the terms.
+    term_match: Option<TermMatch>,
+    #[serde(default)]
+    author_id: Vec<Uuid>,
+    #[serde(default)]
+    media: Vec<Media>,
+    created_after: Option<NaiveDateTime>,
+    /// Exclusive.
+    created_before: Option<NaiveDateTime>,
+    /// Full-text search in the syntax of web search engines, e.g. `"a phrase" or word -excluded`.
+    q: Option<String>,
+    /// Starts at 1.
+    #[validate(range(min = 1))]
+    page: Option<i64>,
+    /// 20 by default, at most 100.
+    #[validate(range(min = 1, max = "MAX_PAGE_SIZE"))]
+    page_size: Option<i64>,
+    /// Newest first by default, or the most relevant with `q`.
+    sort: Option<SnippetSort>,
+    /// `desc` by default for dates and lengths, `asc` for author names and media.
+    order: Option<SortOrder>,
+    /// From the `next` or `prev` link of another page, instead of `page`.
+    cursor: Option<String>,
+    /// Whether to count the snippets and pages, which is always done with `page`.
+    total: Option<bool>,
+}
+
+#[derive(Deserialize, OpenapiType, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TermMatch {
+    Any,
+    All,
+}
+
+#[derive(Deserialize, OpenapiType, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SnippetSort {
+    CreatedAt,
+    UpdatedAt,
+    TextLength,
+    AuthorName,
+    Media,
+}
+
+#[derive(Deserialize, OpenapiType, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl From<SnippetSort> for SortKey {
+    fn from(sort: SnippetSort) -> Self {
+        match sort {
+            SnippetSort::CreatedAt => Self::CreatedAt,
+            SnippetSort::UpdatedAt => Self::UpdatedAt,
+            SnippetSort::TextLength => Self::TextLength,
+            SnippetSort::AuthorName => Self::AuthorName,
+            SnippetSort::Media => Self::Media,
+        }
+    }
+}
+
+impl SnippetQueryStringExtractor {
+    fn sort(&self) -> Option<Sort> {
+        if self.sort.is_none() && self.order.is_none() {
+            return None;
+        }
+        let key = self.sort.unwrap_or(SnippetSort::CreatedAt);
+        let ascending = match self.order {
+            Some(order) => order == SortOrder::Asc,
+            None => matches!(key, SnippetSort::AuthorName | SnippetSort::Media),
+        };
+        Some(Sort {
+            key: key.into(),
+            ascending,
+        })
+    }
+}
+
+impl From<SnippetQueryStringExtractor> for SnippetFilter {
+    fn from(query: SnippetQueryStringExtractor) -> Self {
+        Self {
+            term_ids: query.term_id,
+            all_terms: query.term_match == Some(TermMatch::All),
+            author_ids: query.author_id,
+            media: query.media,
+            created_after: query.created_after,
+            created_before: query.created_before,
+            q: query
+                .q
+                .map(|q| q.trim().to_string())
+                .filter(|q| !q.is_empty()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, OpenapiType, Validate)]
@@ -87,12 +190,59 @@ struct SnippetResponse {
     pub link: Option<String>,
     pub authors: Vec<AuthorResponse>,
     pub terms: Vec<SnippetTermResponse>,
+    /// Fragments of the text around the matches of `q` as HTML, the text is escaped and matches
+    /// are wrapped in `<mark>` tags.
+    pub headline: Option<String>,
 }
 
 #[derive(Serialize, OpenapiType)]
 struct SnippetSearchResponse {
-    pub pages: i64,
+    pub pages: Option<i64>,
+    pub total: Option<i64>,
     pub snippets: Vec<SnippetResponse>,
+    /// Links to the adjacent pages, which stay put when snippets are added. Full-text searches
+    /// are ordered by relevance and only have numbered pages.
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
+/// The keyset of a snippet and the side of it a page is on, opaque to clients.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    created_at: NaiveDateTime,
+    id: Uuid,
+    before: bool,
+}
+
+impl Cursor {
+    fn new(snippet: &SnippetWithRelated, before: bool) -> Self {
+        Self {
+            created_at: snippet.created_at,
+            id: snippet.id,
+            before,
+        }
+    }
+
+    fn encode(&self) -> Result<String, Error> {
+        let json = serde_json::to_vec(self).map_err(|_| Error::InternalServerError)?;
+        Ok(URL_SAFE_NO_PAD.encode(json))
+    }
+
+    fn decode(cursor: &str) -> Result<Self, Error> {
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or(Error::BadRequest("Invalid cursor".to_string()))
+    }
+
+    fn position(&self) -> Position {
+        if self.before {
+            Position::Before(self.created_at, self.id)
+        } else {
+            Position::After(self.created_at, self.id)
+        }
+    }
 }
 
 impl From<SnippetWithRelated> for SnippetResponse {
@@ -112,17 +262,19 @@ impl From<SnippetWithRelated> for SnippetResponse {
                 .into_iter()
                 .map(AuthorResponse::from)
                 .collect(),
+            headline: snippet.headline,
         }
     }
 }
 
+/// Requires the `snippets:write` scope.
 #[create]
 fn create(
     auth: AuthenticationStatus,
     body: CreateSnippet,
     conn: &mut PgConnection,
 ) -> Result<Uuid, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::SnippetsWrite)?;
     body.validate()?;
     let snippet = Snippet::new(body.text, body.media, body.link);
     let uuid = snippets::insert(
@@ -135,42 +287,135 @@ fn create(
     Ok(uuid)
 }
 
+/// Requires the `snippets:read` scope.
 #[read_all]
 fn read_all(
     auth: AuthenticationStatus,
     conn: &mut PgConnection,
 ) -> Result<Vec<SnippetResponse>, Error> {
-    auth.ok()?;
-    let result = load_snippets(None, None, None, conn)?;
+    auth.ok().scope(Scope::SnippetsRead)?;
+    let result = load_snippets(&SnippetFilter::default(), None, Position::Offset(0), conn)?;
     Ok(result)
 }
 
 fn load_snippets(
-    term_id: Option<Uuid>,
+    filter: &SnippetFilter,
     limit: Option<i64>,
-    offset: Option<i64>,
+    position: Position,
     conn: &mut PgConnection,
 ) -> Result<Vec<SnippetResponse>, Error> {
-    let snippets = snippets::search(term_id, limit, offset, conn)?;
+    let snippets = snippets::search(filter, None, limit, position, conn)?;
     let result = snippets.into_iter().map(SnippetResponse::from).collect();
     Ok(result)
 }
 
+/// Without `page` or `cursor` the first page is found. Cursors are only for the default order
+/// without `q`. Requires the `snippets:read` scope.
 #[search]
-fn search(
+async fn search(
+    state: &mut State,
     auth: AuthenticationStatus,
     query: SnippetQueryStringExtractor,
+) -> Result<SnippetSearchResponse, Error> {
+    auth.ok().scope(Scope::SnippetsRead)?;
+    query.validate()?;
+    let page_size = query.page_size.unwrap_or(PAGE_SIZE);
+    let position = match (query.page, &query.cursor) {
+        (Some(_), Some(_)) => return Err(Error::BadRequest("Either page or cursor".to_string())),
+        (Some(page), None) => Position::Offset(
+            (page - 1)
+                .checked_mul(page_size)
+                .ok_or(Error::BadRequest("Page is too large".to_string()))?,
+        ),
+        (None, Some(cursor)) => Cursor::decode(cursor)?.position(),
+        (None, None) => Position::Offset(0),
+    };
+    let sort = query.sort();
+    let with_total = query.page.is_some() || query.total == Some(true);
+    let has_cursor = query.cursor.is_some();
+    let uri_query = Uri::borrow_from(state).query().unwrap_or_default();
+    let link = link_to(uri_query);
+    let filter = SnippetFilter::from(query);
+    let newest = Sort {
+        key: SortKey::CreatedAt,
+        ascending: false,
+    };
+    let keyset = filter.q.is_none() && sort.is_none_or(|sort| sort == newest);
+    if has_cursor && !keyset {
+        return Err(Error::BadRequest(
+            "Cursors need the default order".to_string(),
+        ));
+    }
+    let link = keyset.then_some(link);
+    let repo = Repo::borrow_from(state).clone();
+    repo.run(move |mut conn| {
+        load_page(
+            &filter, sort, page_size, position, with_total, link, &mut conn,
+        )
+    })
+    .await
+}
+
+/// Returns a function making links to another page of the search in `query`.
+fn link_to(query: &str) -> impl Fn(&Cursor) -> Result<String, Error> + Send + 'static {
+    let kept = form_urlencoded::parse(query.as_bytes())
+        .filter(|(key, _)| key != "page" && key != "cursor")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    move |cursor| {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(&kept)
+            .append_pair("cursor", &cursor.encode()?)
+            .finish();
+        Ok(format!("{API_URL}/snippets/search?{query}"))
+    }
+}
+
+/// Links are only made with `link`, when the order of the page allows keysets.
+fn load_page(
+    filter: &SnippetFilter,
+    sort: Option<Sort>,
+    page_size: i64,
+    position: Position,
+    with_total: bool,
+    link: Option<impl Fn(&Cursor) -> Result<String, Error>>,
     conn: &mut PgConnection,
 ) -> Result<SnippetSearchResponse, Error> {
-    auth.ok()?;
-    let limit = PAGE_SIZE;
-    let offset = (query.page - 1) * PAGE_SIZE;
-    let pages = count(query.term_id, PAGE_SIZE, conn)?;
-    let snippets = load_snippets(query.term_id, Some(limit), Some(offset), conn)?;
-    let result = SnippetSearchResponse { pages, snippets };
-    Ok(result)
+    let total = with_total.then(|| count(filter, conn)).transpose()?;
+    let pages = total.map(|total| total / page_size + (total % page_size).signum());
+    // One more than fits shows whether there is another page
+    let mut snippets = snippets::search(filter, sort, Some(page_size + 1), position, conn)?;
+    let more = snippets.len() as i64 > page_size;
+    if more {
+        match position {
+            Position::Before(..) => snippets.remove(0),
+            _ => snippets.remove(snippets.len() - 1),
+        };
+    }
+    let (has_prev, has_next) = match position {
+        Position::Offset(offset) => (offset > 0, more),
+        Position::After(..) => (true, more),
+        Position::Before(..) => (more, true),
+    };
+    let (mut prev, mut next) = (None, None);
+    if let (Some(link), Some(first), Some(last)) = (link, snippets.first(), snippets.last()) {
+        if has_prev {
+            prev = Some(link(&Cursor::new(first, true))?);
+        }
+        if has_next {
+            next = Some(link(&Cursor::new(last, false))?);
+        }
+    }
+    Ok(SnippetSearchResponse {
+        pages,
+        total,
+        snippets: snippets.into_iter().map(SnippetResponse::from).collect(),
+        next,
+        prev,
+    })
 }
 
+/// Requires the `snippets:write` scope.
 #[update]
 fn update(
     auth: AuthenticationStatus,
@@ -178,7 +423,7 @@ fn update(
     body: UpdateSnippet,
     conn: &mut PgConnection,
 ) -> Result<NoContent, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::SnippetsWrite)?;
     body.validate()?;
     snippets::update(
         id,
@@ -193,13 +438,14 @@ fn update(
     .map(|_| NoContent::default())
 }
 
+/// Requires the `snippets:write` scope.
 #[delete]
 fn delete(
     auth: AuthenticationStatus,
     id: Uuid,
     conn: &mut PgConnection,
 ) -> Result<NoContent, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::SnippetsWrite)?;
     snippets::delete(id, conn).map(|_| NoContent::default())
 }
 
@@ -218,9 +464,10 @@ struct MediaStatsResponse {
     pub count: i64,
 }
 
+/// Requires the `snippets:read` scope.
 #[endpoint(uri = "stats", method = "Method::GET", params = false, body = false)]
 fn stats(auth: AuthenticationStatus, conn: &mut PgConnection) -> Result<StatsResponse, Error> {
-    auth.ok()?;
+    auth.ok().scope(Scope::SnippetsRead)?;
     let media_stats = snippets::select_media_stats(conn)?;
     let media = media_stats
         .into_iter()
@@ -229,3 +476,248 @@ fn stats(auth: AuthenticationStatus, conn: &mut PgConnection) -> Result<StatsRes
     let result = StatsResponse { media };
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::db::{authors, terms, users};
+    use crate::models::enums::Role;
+    use crate::models::{Password, Term, User};
+    use crate::resources::auth::{issue_tokens, Client};
+    use crate::router::router;
+    use fake::{Fake, Faker};
+    use gotham::hyper::header::AUTHORIZATION;
+    use gotham::plain::test::AsyncTestServer;
+    use gotham_restful::gotham::hyper::StatusCode;
+    use serde_json::Value;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn term_filter_keeps_all_terms() {
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let password: Password = Faker.fake();
+            let user_id = users::insert(User::fake(Role::User), password.password(), conn)
+                .expect("Can not create");
+            let [filtered, other] = ["Filtered term", "Other term"].map(|name| {
+                terms::insert(Term::new(name.to_string()), Vec::new(), conn)
+                    .expect("Can not create")
+            });
+            let authors =
+                authors::insert(vec!["Filtered author".to_string()], conn).expect("Can not create");
+            let snippet = Snippet::new("Tagged twice".to_string(), Media::Blog, None);
+            snippets::insert(snippet, vec![filtered, other], authors, Vec::new(), conn)
+                .expect("Can not create");
+            let snippet = Snippet::new("Tagged once".to_string(), Media::Blog, None);
+            snippets::insert(snippet, vec![other], Vec::new(), Vec::new(), conn)
+                .expect("Can not create");
+            let response = issue_tokens(user_id, Role::User, Client::default(), conn)
+                .expect("Can not sign in");
+            tx.send((filtered, response.token().to_string()))
+                .expect("Can not send");
+        })
+        .await;
+        let (filtered, token) = rx.await.expect("Can not find term");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+
+        let response = test_server
+            .client()
+            .get(format!(
+                "http://localhost:3000/api/v1/snippets/search?term_id={filtered}&page=1"
+            ))
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let result: Value = serde_json::from_slice(&body).expect("Can not decode JSON");
+        assert_eq!(result["pages"], 1);
+        let snippets = result["snippets"]
+            .as_array()
+            .expect("Can not find snippets");
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0]["text"], "Tagged twice");
+        assert_eq!(snippets[0]["terms"].as_array().map(Vec::len), Some(2));
+        assert_eq!(snippets[0]["authors"].as_array().map(Vec::len), Some(1));
+    }
+
+    #[tokio::test]
+    async fn repeated_filters_are_parsed() {
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let password: Password = Faker.fake();
+            let user_id = users::insert(User::fake(Role::User), password.password(), conn)
+                .expect("Can not create");
+            let [a, b] = ["Repeated term A", "Repeated term B"].map(|name| {
+                terms::insert(Term::new(name.to_string()), Vec::new(), conn)
+                    .expect("Can not create")
+            });
+            for (text, media, terms) in [
+                ("Both terms video", Media::Video, vec![a, b]),
+                ("Both terms book", Media::Book, vec![a, b]),
+                ("One term video", Media::Video, vec![a]),
+            ] {
+                let snippet = Snippet::new(text.to_string(), media, None);
+                snippets::insert(snippet, terms, Vec::new(), Vec::new(), conn)
+                    .expect("Can not create");
+            }
+            let response = issue_tokens(user_id, Role::User, Client::default(), conn)
+                .expect("Can not sign in");
+            tx.send(((a, b), response.token().to_string()))
+                .expect("Can not send");
+        })
+        .await;
+        let ((a, b), token) = rx.await.expect("Can not find terms");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+
+        let response = test_server
+            .client()
+            .get(format!(
+                "http://localhost:3000/api/v1/snippets/search?term_id={a}&term_id={b}\
+                 &term_match=all&media=Video&media=Blog&created_after=2000-01-01T00:00:00"
+            ))
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let result: Value = serde_json::from_slice(&body).expect("Can not decode JSON");
+        let texts: Vec<&str> = result["snippets"]
+            .as_array()
+            .expect("Can not find snippets")
+            .iter()
+            .filter_map(|snippet| snippet["text"].as_str())
+            .collect();
+        assert_eq!(texts, ["Both terms video"]);
+    }
+
+    #[tokio::test]
+    async fn huge_page_is_bad_request() {
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let password: Password = Faker.fake();
+            let user_id = users::insert(User::fake(Role::User), password.password(), conn)
+                .expect("Can not create");
+            let response = issue_tokens(user_id, Role::User, Client::default(), conn)
+                .expect("Can not sign in");
+            tx.send(response.token().to_string()).expect("Can not send");
+        })
+        .await;
+        let token = rx.await.expect("Can not sign in");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+
+        let response = test_server
+            .client()
+            .get(format!(
+                "http://localhost:3000/api/v1/snippets/search?page={}",
+                i64::MAX
+            ))
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn page_size_and_order_are_parsed() {
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let password: Password = Faker.fake();
+            let user_id = users::insert(User::fake(Role::User), password.password(), conn)
+                .expect("Can not create");
+            let term = terms::insert(Term::new("Sorted term".to_string()), Vec::new(), conn)
+                .expect("Can not create");
+            for text in ["Medium", "Long text", "Short"] {
+                let snippet = Snippet::new(text.to_string(), Media::Book, None);
+                snippets::insert(snippet, vec![term], Vec::new(), Vec::new(), conn)
+                    .expect("Can not create");
+            }
+            let response = issue_tokens(user_id, Role::User, Client::default(), conn)
+                .expect("Can not sign in");
+            tx.send((term, response.token().to_string()))
+                .expect("Can not send");
+        })
+        .await;
+        let (term, token) = rx.await.expect("Can not find term");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+        let get = |query: String| {
+            let client = test_server.client();
+            let token = token.clone();
+            async move {
+                client
+                    .get(format!(
+                        "http://localhost:3000/api/v1/snippets/search?{query}"
+                    ))
+                    .header(AUTHORIZATION, format!("Bearer {token}"))
+                    .perform()
+                    .await
+                    .expect("Can not send a request")
+            }
+        };
+
+        let response = get(format!(
+            "term_id={term}&sort=text_length&order=asc&page_size=2&page=1"
+        ))
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let result: Value = serde_json::from_slice(&body).expect("Can not decode JSON");
+        assert_eq!(result["pages"], 2);
+        let texts: Vec<&str> = result["snippets"]
+            .as_array()
+            .expect("Can not find snippets")
+            .iter()
+            .filter_map(|snippet| snippet["text"].as_str())
+            .collect();
+        assert_eq!(texts, ["Short", "Medium"]);
+
+        let response = get(format!("term_id={term}&page_size=101")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = get(format!("page_size={MAX_PAGE_SIZE}&page={}", i64::MAX / 2)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn links_keep_the_filters() {
+        let cursor = Cursor {
+            created_at: NaiveDateTime::default(),
+            id: Uuid::nil(),
+            before: false,
+        };
+        let link =
+            link_to("term_id=a&page=2&media=Blog&media=Book")(&cursor).expect("Can not make link");
+        let encoded = cursor.encode().expect("Can not encode");
+        assert_eq!(
+            link,
+            format!("/api/v1/snippets/search?term_id=a&media=Blog&media=Book&cursor={encoded}")
+        );
+
+        let decoded = Cursor::decode(&encoded).expect("Can not decode");
+        assert_eq!(
+            decoded.position(),
+            Position::After(cursor.created_at, cursor.id)
+        );
+        assert!(Cursor::decode("page").is_err());
+    }
+}
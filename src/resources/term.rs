@@ -1,7 +1,7 @@
 use crate::db::terms;
 use crate::error::Error;
 use crate::models::Term;
-use crate::resources::auth::{AuthenticationStatus, Permission};
+use crate::resources::auth::{AuthenticationStatus, Permission, Scope};
 use crate::resources::validation::terms::MIN_NAME_LENGTH;
 
 use diesel::PgConnection;
@@ -46,24 +46,26 @@ impl From<(Term, Vec<Uuid>)> for TermResponse {
     }
 }
 
+/// Requires the `terms:write` scope.
 #[create]
 fn create(
     auth: AuthenticationStatus,
     body: CreateTerm,
     conn: &mut PgConnection,
 ) -> Result<Uuid, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::TermsWrite)?;
     body.validate()?;
     let term = Term::new(body.name);
     terms::insert(term, body.related, conn)
 }
 
+/// Requires the `terms:read` scope.
 #[read_all]
 fn read_all(
     auth: AuthenticationStatus,
     conn: &mut PgConnection,
 ) -> Result<Vec<TermResponse>, Error> {
-    auth.ok()?;
+    auth.ok().scope(Scope::TermsRead)?;
     let terms = terms::select_all(conn)?;
     let related = terms::select_related(conn)?;
     let results = terms
@@ -82,6 +84,7 @@ fn read_all(
     Ok(results)
 }
 
+/// Requires the `terms:write` scope.
 #[update]
 fn update(
     auth: AuthenticationStatus,
@@ -89,18 +92,19 @@ fn update(
     body: UpdateTerm,
     conn: &mut PgConnection,
 ) -> Result<NoContent, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::TermsWrite)?;
     body.validate()?;
     terms::update(id, body.name, body.related, conn).map(|_| NoContent::default())
 }
 
+/// Requires the `terms:write` scope.
 #[delete]
 fn delete(
     auth: AuthenticationStatus,
     id: Uuid,
     conn: &mut PgConnection,
 ) -> Result<NoContent, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::TermsWrite)?;
     terms::delete(id, conn).map(|_| NoContent::default())
 }
 
@@ -114,9 +118,10 @@ struct TermGraphResponse {
     nodes: Vec<Vec<usize>>,
 }
 
+/// Requires the `terms:read` scope.
 #[endpoint(uri = "graph", method = "Method::GET", params = false, body = false)]
 fn graph(auth: AuthenticationStatus, conn: &mut PgConnection) -> Result<TermGraphResponse, Error> {
-    auth.ok()?;
+    auth.ok().scope(Scope::TermsRead)?;
     let graph = terms::select_graph(conn)?;
     let result = TermGraphResponse {
         terms: graph.0,
@@ -0,0 +1,261 @@
+use crate::authentication::hash_token;
+use crate::config::require_admin_2fa;
+use crate::db::{login_attempts, login_challenges, two_factor, users};
+use crate::error::Error;
+use crate::middleware::ResponseCookies;
+use crate::models::enums::Role;
+use crate::resources::auth::{issue_tokens, AuthenticationStatus, Client, TokenResponse};
+use crate::resources::validation::users::MIN_PASSWORD_LENGTH;
+use crate::router::Repo;
+use crate::totp;
+
+use diesel::{Connection, PgConnection};
+use gotham_restful::gotham::hyper::Method;
+use gotham_restful::gotham::state::{FromState, State};
+use gotham_restful::*;
+use openapi_type::OpenapiType;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Resource)]
+#[resource(enrol_totp, confirm_totp, disable_totp, verify_totp)]
+pub struct Resource;
+
+/// The secret to add to an authenticator app, as base32 text or as an `otpauth://` URI.
+#[derive(Debug, Deserialize, Serialize, OpenapiType)]
+pub struct TotpEnrolment {
+    secret: String,
+    uri: String,
+}
+
+#[derive(Deserialize, Serialize, OpenapiType)]
+struct TotpCode {
+    code: String,
+}
+
+#[derive(Deserialize, Serialize, OpenapiType)]
+struct RecoveryCodes {
+    codes: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, OpenapiType, Validate)]
+struct DisableTotp {
+    #[validate(length(min = "MIN_PASSWORD_LENGTH"))]
+    password: String,
+}
+
+#[derive(Deserialize, Serialize, OpenapiType)]
+struct VerifyChallenge {
+    challenge: String,
+    /// A current code, or one of the recovery codes.
+    code: String,
+}
+
+/// Replaces a pending enrolment. Two-factor authentication is only enabled once a code is
+/// confirmed.
+#[endpoint(uri = "totp", method = "Method::POST", params = false, body = false)]
+fn enrol_totp(auth: AuthenticationStatus, conn: &mut PgConnection) -> Result<TotpEnrolment, Error> {
+    let auth = auth.ok()?;
+    let user = users::select(auth.sub(), conn)?;
+    enrol(auth.sub(), user.email(), conn)
+}
+
+/// Confirms a pending enrolment with a current code. Returns new recovery codes, which replace
+/// any previous ones.
+#[endpoint(
+    uri = "totp/confirm",
+    method = "Method::POST",
+    params = false,
+    body = true
+)]
+fn confirm_totp(
+    auth: AuthenticationStatus,
+    body: TotpCode,
+    conn: &mut PgConnection,
+) -> Result<RecoveryCodes, Error> {
+    let auth = auth.ok()?;
+    conn.transaction::<_, Error, _>(|conn| {
+        two_factor::verify(auth.sub(), &body.code, conn)?;
+        let codes = two_factor::generate_recovery_codes(auth.sub(), conn)?;
+        Ok(RecoveryCodes { codes })
+    })
+}
+
+/// Admins can not disable it while `REQUIRE_ADMIN_2FA` is set.
+#[endpoint(uri = "totp", method = "Method::DELETE", params = false, body = true)]
+fn disable_totp(
+    auth: AuthenticationStatus,
+    body: DisableTotp,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    let auth = auth.ok()?;
+    body.validate()?;
+    if auth.role() == Role::Admin && require_admin_2fa() {
+        return Err(Error::Forbidden);
+    }
+    users::check_password(auth.sub(), &body.password, conn)?;
+    two_factor::disable(auth.sub(), conn).map(|_| NoContent::default())
+}
+
+/// Completes the challenge of `/auth`. Wrong codes count as failed login attempts. Completing an
+/// enrolment that `REQUIRE_ADMIN_2FA` forced also returns the recovery codes.
+#[endpoint(
+    uri = "totp/verify",
+    method = "Method::POST",
+    params = false,
+    body = true
+)]
+async fn verify_totp(state: &mut State, body: VerifyChallenge) -> Result<TokenResponse, Error> {
+    let client = Client::borrow_from(state);
+    let repo = Repo::borrow_from(state).clone();
+    let mut response = repo
+        .run(move |mut conn| verify(body, client, &mut conn))
+        .await?;
+    ResponseCookies::start_session(state, &mut response);
+    Ok(response)
+}
+
+fn verify(
+    body: VerifyChallenge,
+    client: Client,
+    conn: &mut PgConnection,
+) -> Result<TokenResponse, Error> {
+    let ip = client.ip.clone();
+    let challenge = hash_token(&body.challenge);
+    let user = users::select(login_challenges::select_user(&challenge, conn)?, conn)?;
+    login_attempts::check(user.email(), ip.as_deref(), conn)?;
+    let enrolling = !two_factor::is_enabled(*user.id(), conn)?;
+    if let Err(e) = two_factor::verify(*user.id(), &body.code, conn) {
+        login_attempts::record_failure(user.email(), ip.as_deref(), conn)?;
+        return Err(e);
+    }
+    conn.transaction::<_, Error, _>(|conn| {
+        login_challenges::consume(&challenge, conn)?;
+        login_attempts::clear(Some(user.email()), None, conn)?;
+        let recovery_codes = match enrolling {
+            true => Some(two_factor::generate_recovery_codes(*user.id(), conn)?),
+            false => None,
+        };
+        let response = issue_tokens(*user.id(), *user.role(), client, conn)?;
+        Ok(response.with_recovery_codes(recovery_codes))
+    })
+}
+
+pub(crate) fn enrol(
+    user_id: Uuid,
+    email: &str,
+    conn: &mut PgConnection,
+) -> Result<TotpEnrolment, Error> {
+    let secret = totp::generate_secret();
+    two_factor::enrol(user_id, &secret, conn)?;
+    Ok(TotpEnrolment {
+        secret: totp::base32(&secret),
+        uri: totp::provisioning_uri(&secret, email),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::models::{Password, User};
+    use crate::resources::auth::{ChallengeResponse, LoginResponse};
+    use crate::router::router;
+    use chrono::Utc;
+    use fake::{Fake, Faker};
+    use gotham::mime::APPLICATION_JSON;
+    use gotham::plain::test::AsyncTestServer;
+    use gotham_restful::gotham::hyper::StatusCode;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn forced_enrolment_returns_recovery_codes() {
+        run_migrations_and_test_transactions(|conn| {
+            let user = User::fake(Role::Admin);
+            let password: Password = Faker.fake();
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+            let secret = totp::generate_secret();
+            two_factor::enrol(user_id, &secret, conn).expect("Can not enrol");
+            login_challenges::insert(user_id, hash_token("challenge"), conn)
+                .expect("Can not create");
+            let body = VerifyChallenge {
+                challenge: "challenge".to_string(),
+                code: totp::code(&secret, Utc::now().timestamp()),
+            };
+            let response = verify(body, Client::default(), conn).expect("Can not verify");
+            let response = serde_json::to_value(response).expect("Can not encode JSON");
+            let codes = response["recovery_codes"]
+                .as_array()
+                .expect("Can not find recovery codes");
+            let code = codes[0].as_str().expect("Invalid code").to_string();
+
+            login_challenges::insert(user_id, hash_token("again"), conn).expect("Can not create");
+            let body = VerifyChallenge {
+                challenge: "again".to_string(),
+                code,
+            };
+            let response = verify(body, Client::default(), conn).expect("Can not verify");
+            let response = serde_json::to_value(response).expect("Can not encode JSON");
+            assert_eq!(response["recovery_codes"], serde_json::Value::Null);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn sign_in_takes_two_steps() {
+        let user = User::fake(Role::User);
+        let password: Password = Faker.fake();
+        let email = user.email().clone();
+        let user_password = password.password().clone();
+        let secret = totp::generate_secret();
+        let enrolled = secret.clone();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let user_id = users::insert(user, password.password(), conn).expect("Can not create");
+            two_factor::enrol(user_id, &enrolled, conn).expect("Can not enrol");
+            let code = totp::code(&enrolled, Utc::now().timestamp() - 30);
+            two_factor::verify(user_id, &code, conn).expect("Can not confirm");
+        })
+        .await;
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+
+        let body = json!({"email": email, "password": user_password}).to_string();
+        let response = test_server
+            .client()
+            .post("http://localhost:3000/api/v1/auth")
+            .body(body)
+            .mime(APPLICATION_JSON)
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let challenge = match serde_json::from_slice(&body).expect("Can not decode JSON") {
+            LoginResponse::Challenge(ChallengeResponse { challenge, .. }) => challenge,
+            LoginResponse::Token(_) => panic!("Signed in without a second factor"),
+        };
+
+        let code = totp::code(&secret, Utc::now().timestamp());
+        for status in [StatusCode::OK, StatusCode::UNAUTHORIZED] {
+            let request = VerifyChallenge {
+                challenge: challenge.clone(),
+                code: code.clone(),
+            };
+            let body = serde_json::to_string(&request).expect("Can not encode JSON");
+            let response = test_server
+                .client()
+                .post("http://localhost:3000/api/v1/auth/totp/verify")
+                .body(body)
+                .mime(APPLICATION_JSON)
+                .perform()
+                .await
+                .expect("Can not send a request");
+            assert_eq!(response.status(), status);
+        }
+    }
+}
@@ -1,10 +1,13 @@
-use crate::db::users;
+use crate::db::{sessions, users};
 use crate::error::Error;
-use crate::models::{enums::Role, User};
-use crate::resources::auth::{AuthenticationStatus, Permission};
+use crate::models::{enums::Role, Session, User};
+use crate::resources::auth::{AuthenticationStatus, Permission, Scope};
 use crate::resources::validation::users::*;
 
+use chrono::NaiveDateTime;
 use diesel::PgConnection;
+use gotham_derive::{StateData, StaticResponseExtender};
+use gotham_restful::gotham::hyper::Method;
 use gotham_restful::*;
 use openapi_type::OpenapiType;
 use serde_derive::{Deserialize, Serialize};
@@ -12,7 +15,22 @@ use uuid::Uuid;
 use validator::Validate;
 
 #[derive(Resource)]
-#[resource(create, read, read_all, update, delete)]
+#[resource(
+    create,
+    read,
+    read_all,
+    update,
+    update_profile,
+    delete,
+    read_deleted,
+    restore,
+    purge,
+    change_password,
+    reset_password,
+    read_sessions,
+    delete_sessions,
+    delete_session
+)]
 pub struct Resource;
 
 #[derive(Serialize, Deserialize, OpenapiType, Validate)]
@@ -20,7 +38,7 @@ pub struct CreateUser {
     #[validate(length(min = "MIN_NAME_LENGTH"))]
     pub name: String,
     pub role: Role,
-    #[validate(length(min = "MIN_EMAIL_LENGTH"))]
+    #[validate(email, length(min = "MIN_EMAIL_LENGTH"))]
     pub email: String,
     #[validate(length(min = "MIN_PASSWORD_LENGTH"))]
     pub password: String,
@@ -31,10 +49,47 @@ pub struct UpdateUser {
     #[validate(length(min = "MIN_NAME_LENGTH"))]
     pub name: String,
     pub role: Role,
-    #[validate(length(min = "MIN_EMAIL_LENGTH"))]
+    #[validate(email, length(min = "MIN_EMAIL_LENGTH"))]
     pub email: String,
 }
 
+/// The `role` is left out on purpose, only admins change it.
+#[derive(Serialize, Deserialize, OpenapiType, Validate)]
+pub struct UpdateProfile {
+    #[validate(length(min = "MIN_NAME_LENGTH"))]
+    pub name: String,
+    #[validate(email, length(min = "MIN_EMAIL_LENGTH"))]
+    pub email: String,
+    /// The current password, required to change the email.
+    #[validate(length(min = "MIN_PASSWORD_LENGTH"))]
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender, OpenapiType, Clone)]
+pub(crate) struct UserPath {
+    pub id: Uuid,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender, OpenapiType, Clone)]
+struct SessionPath {
+    id: Uuid,
+    session_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, OpenapiType, Validate)]
+pub struct ChangePassword {
+    #[validate(length(min = "MIN_PASSWORD_LENGTH"))]
+    pub current_password: String,
+    #[validate(length(min = "MIN_PASSWORD_LENGTH"))]
+    pub new_password: String,
+}
+
+#[derive(Serialize, Deserialize, OpenapiType, Validate)]
+pub struct ResetPassword {
+    #[validate(length(min = "MIN_PASSWORD_LENGTH"))]
+    pub password: String,
+}
+
 #[derive(Serialize, OpenapiType)]
 struct UserResponse {
     id: Uuid,
@@ -43,6 +98,29 @@ struct UserResponse {
     role: Role,
 }
 
+#[derive(Serialize, OpenapiType)]
+struct SessionResponse {
+    id: Uuid,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    created_at: NaiveDateTime,
+    /// Whether the request was made with a token of this session.
+    current: bool,
+}
+
+impl SessionResponse {
+    fn new(session: Session, sid: Option<Uuid>) -> Self {
+        let (id, _, ip, user_agent, created_at) = session.dissolve();
+        Self {
+            id,
+            ip,
+            user_agent,
+            created_at,
+            current: sid == Some(id),
+        }
+    }
+}
+
 impl From<User> for UserResponse {
     fn from(user: User) -> Self {
         let (id, name, email, role, _, _, _) = user.dissolve();
@@ -55,19 +133,22 @@ impl From<User> for UserResponse {
     }
 }
 
+/// Requires the `users:write` scope.
 #[create]
 fn create(
     auth: AuthenticationStatus,
-    body: CreateUser,
+    mut body: CreateUser,
     conn: &mut PgConnection,
 ) -> Result<Uuid, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::UsersWrite)?;
+    body.email = normalize_email(&body.email);
     body.validate()?;
     let user = User::new(body.name, body.email, body.role);
     let uuid = users::insert(user, &body.password, conn)?;
     Ok(uuid)
 }
 
+/// Requires the `users:read` scope, except for reading the own account.
 #[read]
 fn read(
     auth: AuthenticationStatus,
@@ -78,46 +159,232 @@ fn read(
     users::select(id, conn).map(UserResponse::from)
 }
 
+/// Requires the `users:read` scope.
 #[read_all]
 fn read_all(
     auth: AuthenticationStatus,
     conn: &mut PgConnection,
 ) -> Result<Vec<UserResponse>, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::UsersRead)?;
     let result = users::select_all(conn)?;
     Ok(result.into_iter().map(UserResponse::from).collect())
 }
 
+/// Requires the `users:write` scope.
 #[update]
 fn update(
     auth: AuthenticationStatus,
     id: Uuid,
-    body: UpdateUser,
+    mut body: UpdateUser,
     conn: &mut PgConnection,
 ) -> Result<NoContent, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::UsersWrite)?;
+    body.email = normalize_email(&body.email);
     body.validate()?;
     users::update(id, body.name, body.email, body.role, conn).map(|_| NoContent::default())
 }
 
+/// Only for the own account, and not with an API key.
+#[endpoint(
+    uri = ":id/profile",
+    method = "Method::PUT",
+    params = false,
+    body = true
+)]
+fn update_profile(
+    auth: AuthenticationStatus,
+    path: UserPath,
+    mut body: UpdateProfile,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    auth.ok().owner(path.id)?;
+    body.email = normalize_email(&body.email);
+    body.validate()?;
+    users::update_profile(
+        path.id,
+        body.name,
+        body.email,
+        body.password.as_deref(),
+        conn,
+    )
+    .map(|_| NoContent::default())
+}
+
+/// Requires the `users:write` scope.
 #[delete]
 fn delete(
     auth: AuthenticationStatus,
     id: Uuid,
     conn: &mut PgConnection,
 ) -> Result<NoContent, Error> {
-    auth.ok().admin()?;
+    auth.ok().scope(Scope::UsersWrite)?;
     users::delete(id, conn).map(|_| NoContent::default())
 }
 
+/// Requires the `users:read` scope.
+#[endpoint(uri = "deleted", method = "Method::GET", params = false, body = false)]
+fn read_deleted(
+    auth: AuthenticationStatus,
+    conn: &mut PgConnection,
+) -> Result<Vec<UserResponse>, Error> {
+    auth.ok().scope(Scope::UsersRead)?;
+    let result = users::select_deleted(conn)?;
+    Ok(result.into_iter().map(UserResponse::from).collect())
+}
+
+/// Requires the `users:write` scope. Fails while another account uses the email.
+#[endpoint(
+    uri = ":id/restore",
+    method = "Method::PUT",
+    params = false,
+    body = false
+)]
+fn restore(
+    auth: AuthenticationStatus,
+    path: UserPath,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    auth.ok().scope(Scope::UsersWrite)?;
+    users::restore(path.id, conn).map(|_| NoContent::default())
+}
+
+/// Requires the `users:write` scope. Only deleted users can be purged.
+#[endpoint(
+    uri = ":id/purge",
+    method = "Method::DELETE",
+    params = false,
+    body = false
+)]
+fn purge(
+    auth: AuthenticationStatus,
+    path: UserPath,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    auth.ok().scope(Scope::UsersWrite)?;
+    users::purge(path.id, conn).map(|_| NoContent::default())
+}
+
+#[endpoint(
+    uri = ":id/password",
+    method = "Method::PUT",
+    params = false,
+    body = true
+)]
+fn change_password(
+    auth: AuthenticationStatus,
+    path: UserPath,
+    body: ChangePassword,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    let auth = auth.ok().owner(path.id)?;
+    body.validate()?;
+    users::change_password(
+        path.id,
+        &body.current_password,
+        &body.new_password,
+        auth.sid(),
+        conn,
+    )
+    .map(|_| NoContent::default())
+}
+
+/// Requires the `users:write` scope.
+#[endpoint(
+    uri = ":id/password/reset",
+    method = "Method::PUT",
+    params = false,
+    body = true
+)]
+fn reset_password(
+    auth: AuthenticationStatus,
+    path: UserPath,
+    body: ResetPassword,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    auth.ok().scope(Scope::UsersWrite)?;
+    body.validate()?;
+    users::reset_password(path.id, &body.password, conn).map(|_| NoContent::default())
+}
+
+/// Signed in sessions that have not expired or been revoked. Requires the `users:read` scope,
+/// except for the own sessions.
+#[endpoint(
+    uri = ":id/sessions",
+    method = "Method::GET",
+    params = false,
+    body = false
+)]
+fn read_sessions(
+    auth: AuthenticationStatus,
+    path: UserPath,
+    conn: &mut PgConnection,
+) -> Result<Vec<SessionResponse>, Error> {
+    let auth = auth.ok();
+    let sid = auth.as_ref().map(|auth| auth.sid()).ok();
+    auth.user(path.id)?;
+    let result = sessions::select_active(path.id, conn)?;
+    Ok(result
+        .into_iter()
+        .map(|session| SessionResponse::new(session, sid))
+        .collect())
+}
+
+/// Signs out everywhere, including the current session. Requires the `users:write` scope, except
+/// for the own sessions without an API key.
+#[endpoint(
+    uri = ":id/sessions",
+    method = "Method::DELETE",
+    params = false,
+    body = false
+)]
+fn delete_sessions(
+    auth: AuthenticationStatus,
+    path: UserPath,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    auth.ok().user_write(path.id)?;
+    sessions::revoke_all(path.id, conn).map(|_| NoContent::default())
+}
+
+/// Requires the `users:write` scope, except for the own sessions without an API key.
+#[endpoint(
+    uri = ":id/sessions/:session_id",
+    method = "Method::DELETE",
+    params = false,
+    body = false
+)]
+fn delete_session(
+    auth: AuthenticationStatus,
+    path: SessionPath,
+    conn: &mut PgConnection,
+) -> Result<NoContent, Error> {
+    auth.ok().user_write(path.id)?;
+    sessions::revoke(path.id, path.session_id, conn).map(|_| NoContent::default())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::authentication::{generate_token, hash_token};
+    use crate::db::api_keys;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use crate::models::ApiKey;
+    use crate::models::Password as UserPassword;
+    use crate::resources::api_key::API_KEY_PREFIX;
+    use crate::resources::auth::{issue_tokens, Client};
+    use crate::router::router;
+    use fake::Faker;
     use fake::{
         faker::internet::{en::FreeEmail, en::Password},
         faker::name::en::FirstName,
         Fake,
     };
+    use gotham::hyper::header::AUTHORIZATION;
+    use gotham::mime::APPLICATION_JSON;
+    use gotham::plain::test::AsyncTestServer;
+    use gotham_restful::gotham::hyper::StatusCode;
+    use serde_json::{json, Value};
+    use tokio::sync::oneshot;
 
     impl CreateUser {
         fn test_name(name: &str) -> Self {
@@ -175,10 +442,16 @@ mod tests {
 
     #[test]
     fn update_short_email_validates() {
-        let update = UpdateUser::test_email("123456");
+        let update = UpdateUser::test_email("a@b.io");
         assert!(update.validate().is_ok());
     }
 
+    #[test]
+    fn update_malformed_email_does_not_validate() {
+        let update = UpdateUser::test_email("123456");
+        assert!(update.validate().is_err());
+    }
+
     #[test]
     fn update_very_short_email_does_not_validate() {
         let update = UpdateUser::test_email("12345");
@@ -208,4 +481,211 @@ mod tests {
         let create = CreateUser::test_password("12345");
         assert!(create.validate().is_err());
     }
+
+    #[test]
+    fn change_very_short_password_does_not_validate() {
+        let change = ChangePassword {
+            current_password: "123456".to_string(),
+            new_password: "12345".to_string(),
+        };
+        assert!(change.validate().is_err());
+
+        let reset = ResetPassword {
+            password: "12345".to_string(),
+        };
+        assert!(reset.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn sessions_are_listed_and_revoked() {
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let [user_id, other_id] = [(); 2].map(|_| {
+                let password: UserPassword = Faker.fake();
+                users::insert(User::fake(Role::User), password.password(), conn)
+                    .expect("Can not create")
+            });
+            let mut sign_in = |user_id: Uuid, user_agent: &str| {
+                let client = Client {
+                    ip: None,
+                    user_agent: Some(user_agent.to_string()),
+                };
+                let response =
+                    issue_tokens(user_id, Role::User, client, conn).expect("Can not sign in");
+                let response = serde_json::to_value(response).expect("Can not encode JSON");
+                response["token"]
+                    .as_str()
+                    .expect("Can not find token")
+                    .to_string()
+            };
+            let token = sign_in(user_id, "First");
+            sign_in(user_id, "Second");
+            let other = sign_in(other_id, "Other");
+            tx.send((user_id, token, other)).expect("Can not send");
+        })
+        .await;
+        let (user_id, token, other) = rx.await.expect("Can not find tokens");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+        let url = format!("http://localhost:3000/api/v1/users/{user_id}/sessions");
+        let list = |token: String| {
+            let client = test_server.client();
+            let url = url.clone();
+            async move {
+                let response = client
+                    .get(url)
+                    .header(AUTHORIZATION, format!("Bearer {token}"))
+                    .perform()
+                    .await
+                    .expect("Can not send a request");
+                let status = response.status();
+                let body = response
+                    .read_body()
+                    .await
+                    .expect("Can not get response body");
+                (status, serde_json::from_slice::<Value>(&body).ok())
+            }
+        };
+
+        let (status, _) = list(other).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, sessions) = list(token.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let sessions = sessions.expect("Can not decode JSON");
+        let sessions = sessions.as_array().expect("Can not find sessions");
+        assert_eq!(sessions.len(), 2);
+        let second = sessions
+            .iter()
+            .find(|session| session["current"] == false)
+            .expect("Can not find other session");
+        assert_eq!(second["user_agent"], "Second");
+
+        let response = test_server
+            .client()
+            .delete(format!(
+                "{url}/{}",
+                second["id"].as_str().expect("Can not find id")
+            ))
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let (_, sessions) = list(token).await;
+        let sessions = sessions.expect("Can not decode JSON");
+        assert_eq!(sessions.as_array().map(Vec::len), Some(1));
+        assert_eq!(sessions[0]["current"], true);
+    }
+
+    #[tokio::test]
+    async fn read_only_key_can_not_sign_out_others() {
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let [admin_id, user_id] = [Role::Admin, Role::User].map(|role| {
+                let password: UserPassword = Faker.fake();
+                users::insert(User::fake(role), password.password(), conn).expect("Can not create")
+            });
+            issue_tokens(user_id, Role::User, Client::default(), conn).expect("Can not sign in");
+            let active = sessions::select_active(user_id, conn).expect("Can not select");
+            let session_id = *active[0].id();
+            let mut create_key = |user_id: Uuid, role: Role, scope: Scope| {
+                let key = format!("{API_KEY_PREFIX}{}", generate_token());
+                let api_key = ApiKey::new(
+                    user_id,
+                    "Reviewer".to_string(),
+                    key[..8].to_string(),
+                    hash_token(&key),
+                    role,
+                    None,
+                    vec![scope.as_str().to_string()],
+                );
+                api_keys::insert(api_key, conn).expect("Can not create");
+                key
+            };
+            let admin_key = create_key(admin_id, Role::Admin, Scope::UsersRead);
+            let own_key = create_key(user_id, Role::User, Scope::SnippetsRead);
+            tx.send((user_id, session_id, admin_key, own_key))
+                .expect("Can not send");
+        })
+        .await;
+        let (user_id, session_id, admin_key, own_key) = rx.await.expect("Can not find key");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+        let url = format!("http://localhost:3000/api/v1/users/{user_id}/sessions");
+        for key in [&admin_key, &own_key] {
+            for url in [url.clone(), format!("{url}/{session_id}")] {
+                let response = test_server
+                    .client()
+                    .delete(url)
+                    .header(AUTHORIZATION, format!("Bearer {key}"))
+                    .perform()
+                    .await
+                    .expect("Can not send a request");
+                assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            }
+        }
+        let response = test_server
+            .client()
+            .get(url)
+            .header(AUTHORIZATION, format!("Bearer {admin_key}"))
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn api_key_can_not_change_own_account() {
+        let (tx, rx) = oneshot::channel();
+        let repo = run_migrations_and_test_transactions(move |conn| {
+            let password: UserPassword = Faker.fake();
+            let user_id = users::insert(User::fake(Role::User), password.password(), conn)
+                .expect("Can not create");
+            let key = format!("{API_KEY_PREFIX}{}", generate_token());
+            let api_key = ApiKey::new(
+                user_id,
+                "Reader".to_string(),
+                key[..8].to_string(),
+                hash_token(&key),
+                Role::User,
+                None,
+                vec![Scope::SnippetsRead.as_str().to_string()],
+            );
+            api_keys::insert(api_key, conn).expect("Can not create");
+            tx.send((user_id, password.password().to_string(), key))
+                .expect("Can not send");
+        })
+        .await;
+        let (user_id, password, key) = rx.await.expect("Can not find key");
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+        let url = format!("http://localhost:3000/api/v1/users/{user_id}");
+        let requests = [
+            (
+                format!("{url}/profile"),
+                json!({"name": "Renamed", "email": "renamed@example.com", "password": password}),
+            ),
+            (
+                format!("{url}/password"),
+                json!({"current_password": password, "new_password": "A new password"}),
+            ),
+        ];
+        for (url, body) in requests {
+            let response = test_server
+                .client()
+                .put(url)
+                .body(body.to_string())
+                .mime(APPLICATION_JSON)
+                .header(AUTHORIZATION, format!("Bearer {key}"))
+                .perform()
+                .await
+                .expect("Can not send a request");
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+    }
 }
@@ -7,11 +7,34 @@ pub mod authors {
 }
 
 pub mod users {
+    use unicode_normalization::UnicodeNormalization;
+
     pub const MIN_NAME_LENGTH: usize = 1;
     pub const MIN_EMAIL_LENGTH: usize = 6;
     pub const MIN_PASSWORD_LENGTH: usize = 6;
+
+    /// Trims and composes (NFC) an email, so the same address is always stored alike. The case
+    /// is kept as entered, the database compares emails case-insensitively.
+    pub fn normalize_email(email: &str) -> String {
+        email.trim().nfc().collect()
+    }
 }
 
 pub mod snippets {
     pub const MIN_TEXT_LENGTH: usize = 1;
 }
+
+pub mod api_keys {
+    pub const MIN_NAME_LENGTH: usize = 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::users::normalize_email;
+
+    #[test]
+    fn email_is_trimmed_and_composed() {
+        assert_eq!(normalize_email(" Foo@Bar.com\n"), "Foo@Bar.com");
+        assert_eq!(normalize_email("jose\u{301}@x.com"), "jos\u{e9}@x.com");
+    }
+}
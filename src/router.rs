@@ -1,12 +1,19 @@
-use crate::config::{app_url, jwt_secret};
+use crate::config::{app_url, cookie_auth};
+use crate::jwt::keyring;
+use crate::middleware::{
+    ApiKeyMiddleware, CookieAuth, CookieMiddleware, JwtMiddleware, RevocationMiddleware,
+    CSRF_HEADER,
+};
 use crate::resources;
-use crate::resources::auth::AuthData;
 
 use diesel::PgConnection;
-use gotham::hyper::header::{AUTHORIZATION, CONTENT_TYPE};
-use gotham::hyper::Method;
+use gotham::helpers::http::response::create_response;
+use gotham::hyper::header::{HeaderName, AUTHORIZATION, CONTENT_TYPE};
+use gotham::hyper::{Body, Method, Response, StatusCode};
+use gotham::mime::APPLICATION_JSON;
 use gotham::router::builder::{self, DefineSingleRoute, DrawRoutes};
 use gotham::router::Router;
+use gotham::state::State;
 use gotham_middleware_diesel::DieselMiddleware;
 use gotham_restful::cors::{Headers, Origin};
 use gotham_restful::gotham::handler::FileOptions;
@@ -16,35 +23,43 @@ use gotham_restful::*;
 
 pub type Repo = gotham_middleware_diesel::Repo<PgConnection>;
 
-static API_URL: &str = "/api/v1";
+pub(crate) static API_URL: &str = "/api/v1";
 
+/// Browsers only send cookies to a single allowed origin.
 #[cfg(debug_assertions)]
-fn cors_origin() -> Origin {
-    Origin::Star
+fn cors_origin(cookie_auth: bool) -> Origin {
+    match cookie_auth {
+        true => Origin::Single(app_url()),
+        false => Origin::Star,
+    }
 }
 
 #[cfg(not(debug_assertions))]
-fn cors_origin() -> Origin {
+fn cors_origin(_cookie_auth: bool) -> Origin {
     Origin::Single(app_url())
 }
 
-fn api_router(repo: Repo) -> Router {
-    let auth: AuthMiddleware<AuthData, _> = AuthMiddleware::new(
-        AuthSource::AuthorizationHeader,
-        AuthValidation::default(),
-        StaticAuthHandler::from_array(jwt_secret().as_ref()),
-    );
+fn api_router(repo: Repo, cookie_auth: bool) -> Router {
     let (chain, pipelines) = single_pipeline(
         new_pipeline()
             .add(DieselMiddleware::new(repo))
             .add(RequestLogger::new(log::Level::Info))
             .add(CorsConfig {
-                origin: cors_origin(),
-                headers: Headers::List(vec![CONTENT_TYPE, AUTHORIZATION]),
+                origin: cors_origin(cookie_auth),
+                headers: Headers::List(vec![
+                    CONTENT_TYPE,
+                    AUTHORIZATION,
+                    HeaderName::from_static(CSRF_HEADER),
+                ]),
                 max_age: 86400,
-                credentials: false,
+                credentials: cookie_auth,
+            })
+            .add(CookieMiddleware)
+            .add(JwtMiddleware {
+                cookie_auth: CookieAuth(cookie_auth),
             })
-            .add(auth)
+            .add(RevocationMiddleware)
+            .add(ApiKeyMiddleware)
             .build(),
     );
     builder::build_router(chain, pipelines, |route| {
@@ -60,8 +75,13 @@ fn api_router(repo: Repo) -> Router {
             route.resource::<resources::author::Resource>("authors");
             route.resource::<resources::term::GraphResource>("terms");
             route.resource::<resources::user::Resource>("users");
+            route.resource::<resources::export::Resource>("users");
             route.resource::<resources::translation::Resource>("translations");
             route.resource::<resources::auth::Resource>("auth");
+            route.resource::<resources::two_factor::Resource>("auth");
+            route.resource::<resources::oidc::Resource>("auth");
+            route.resource::<resources::api_key::Resource>("keys");
+            route.resource::<resources::invitation::Resource>("invitations");
             route.openapi_spec("openapi");
             route.openapi_doc("api_doc");
         });
@@ -74,13 +94,24 @@ fn api_router(repo: Repo) -> Router {
             route.cors("/snippets/stats", method.clone());
             route.cors("/authors", method.clone());
             route.cors("/translations", method.clone());
+            route.cors("/keys", method.clone());
+            route.cors("/invitations", method.clone());
+            route.cors("/invitations/accept", method.clone());
         }
     })
 }
 
 pub fn router(repo: Repo) -> Router {
+    router_with(repo, cookie_auth())
+}
+
+/// Keeps sessions in cookies with `cookie_auth`, which `router` takes from `COOKIE_AUTH`.
+pub(crate) fn router_with(repo: Repo, cookie_auth: bool) -> Router {
     builder::build_simple_router(|route| {
-        route.delegate(API_URL).to_router(api_router(repo.clone()));
+        route
+            .delegate(API_URL)
+            .to_router(api_router(repo.clone(), cookie_auth));
+        route.get("/.well-known/jwks.json").to(jwks);
 
         route.get("/").to_file("frontend/dist/index.html");
         route
@@ -99,9 +130,53 @@ pub fn router(repo: Repo) -> Router {
     })
 }
 
+/// Public keys for other services to verify the issued tokens, including retired ones.
+fn jwks(state: State) -> (State, Response<Body>) {
+    let body = serde_json::to_string(keyring().jwks()).expect("Can not encode JWKS");
+    let response = create_response(&state, StatusCode::OK, APPLICATION_JSON, body);
+    (state, response)
+}
+
 fn file_options(path: &str) -> FileOptions {
     FileOptions::new(path)
         .with_cache_control("public, max-age=604800, immutable")
         .with_brotli(true)
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::migrations::run_migrations_and_test_transactions;
+    use gotham::plain::test::AsyncTestServer;
+    use jsonwebtoken::jwk::JwkSet;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn jwks_has_signing_key() {
+        let repo = run_migrations_and_test_transactions(|_| {}).await;
+        let test_server = AsyncTestServer::new(router(repo))
+            .await
+            .expect("Can not start test server");
+        let response = test_server
+            .client()
+            .get("http://localhost:3000/.well-known/jwks.json")
+            .perform()
+            .await
+            .expect("Can not send a request");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .read_body()
+            .await
+            .expect("Can not get response body");
+        let jwks: JwkSet = serde_json::from_slice(&body).expect("Can not decode JWKS");
+        let token = keyring()
+            .encode(&json!({"sub": "foo"}))
+            .expect("Can not encode");
+        let kid = jsonwebtoken::decode_header(&token)
+            .expect("Can not decode header")
+            .kid
+            .expect("Can not find kid");
+        assert!(jwks.find(&kid).is_some());
+    }
+}
@@ -1,13 +1,55 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "login_subject_enum"))]
+    pub struct LoginSubjectEnum;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "media_enum"))]
     pub struct MediaEnum;
 
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "regconfig", schema = "pg_catalog"))]
+    pub struct Regconfig;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "role_enum"))]
     pub struct RoleEnum;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "tsvector", schema = "pg_catalog"))]
+    pub struct Tsvector;
+}
+
+diesel::table! {
+    access_tokens (jti) {
+        jti -> Uuid,
+        user_id -> Uuid,
+        family_id -> Uuid,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::RoleEnum;
+
+    api_keys (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Varchar,
+        prefix -> Varchar,
+        key_hash -> Text,
+        role -> RoleEnum,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        scopes -> Array<Text>,
+    }
 }
 
 diesel::table! {
@@ -26,6 +68,69 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::RoleEnum;
+
+    invitations (id) {
+        id -> Uuid,
+        email -> Varchar,
+        role -> RoleEnum,
+        token_hash -> Text,
+        invited_by -> Nullable<Uuid>,
+        expires_at -> Timestamp,
+        accepted_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::LoginSubjectEnum;
+
+    login_attempts (subject, identifier) {
+        subject -> LoginSubjectEnum,
+        identifier -> Varchar,
+        failures -> Int4,
+        last_failed_at -> Timestamp,
+        locked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    login_challenges (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    oidc_logins (id) {
+        id -> Uuid,
+        state_hash -> Text,
+        nonce -> Text,
+        code_verifier -> Text,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    password_resets (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     passwords (user_id) {
         user_id -> Uuid,
@@ -35,9 +140,44 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    recovery_codes (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        code_hash -> Text,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        family_id -> Uuid,
+        token_hash -> Text,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        ip -> Nullable<Text>,
+        user_agent -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::MediaEnum;
+    use super::sql_types::Regconfig;
+    use super::sql_types::Tsvector;
 
     snippets (id) {
         id -> Uuid,
@@ -46,6 +186,8 @@ diesel::table! {
         link -> Nullable<Text>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        search_language -> Regconfig,
+        text_search -> Tsvector,
     }
 }
 
@@ -72,6 +214,25 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    totp_secrets (user_id) {
+        user_id -> Uuid,
+        secret -> Bytea,
+        last_used_step -> Nullable<Int8>,
+        confirmed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    user_identities (issuer, subject) {
+        issuer -> Text,
+        subject -> Text,
+        user_id -> Uuid,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::RoleEnum;
@@ -88,19 +249,41 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(access_tokens -> users (user_id));
+diesel::joinable!(api_keys -> users (user_id));
 diesel::joinable!(authors_snippets -> authors (author_id));
 diesel::joinable!(authors_snippets -> snippets (snippet_id));
+diesel::joinable!(invitations -> users (invited_by));
+diesel::joinable!(login_challenges -> users (user_id));
+diesel::joinable!(password_resets -> users (user_id));
 diesel::joinable!(passwords -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(refresh_tokens -> users (user_id));
+diesel::joinable!(sessions -> users (user_id));
 diesel::joinable!(terms_snippets -> snippets (snippet_id));
 diesel::joinable!(terms_snippets -> terms (term_id));
+diesel::joinable!(totp_secrets -> users (user_id));
+diesel::joinable!(user_identities -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    access_tokens,
+    api_keys,
     authors,
     authors_snippets,
+    invitations,
+    login_attempts,
+    login_challenges,
+    oidc_logins,
+    password_resets,
     passwords,
+    recovery_codes,
+    refresh_tokens,
+    sessions,
     snippets,
     terms,
     terms_related,
     terms_snippets,
+    totp_secrets,
+    user_identities,
     users,
 );
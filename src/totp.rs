@@ -0,0 +1,193 @@
+use crate::config::totp_encryption_key;
+use crate::error::Error;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{error, warn};
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hmac;
+use std::sync::OnceLock;
+
+static CIPHER: OnceLock<LessSafeKey> = OnceLock::new();
+
+/// Seconds a code is valid for, as recommended by RFC 6238.
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_LENGTH: usize = 20;
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LENGTH];
+    rand::rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// The step of `now` or a neighbouring one whose code matches, to allow for clock drift.
+pub fn verify(secret: &[u8], code: &str, now: i64) -> Option<i64> {
+    let code = code.trim().parse::<u32>().ok()?;
+    let step = now / STEP_SECONDS;
+    (step - 1..=step + 1).find(|step| hotp(secret, *step as u64) == code)
+}
+
+/// The code an authenticator app shows at `now`.
+pub fn code(secret: &[u8], now: i64) -> String {
+    let code = hotp(secret, (now / STEP_SECONDS) as u64);
+    format!("{code:0width$}", width = DIGITS as usize)
+}
+
+/// Ten characters from the same alphabet as the secret, so they are easy to type.
+pub fn recovery_code() -> String {
+    let mut bytes = [0u8; 10];
+    rand::rng().fill_bytes(&mut bytes);
+    base32(&bytes)[..10].to_string()
+}
+
+/// Recovery codes are accepted regardless of case and surrounding space.
+pub fn normalize(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+/// The `otpauth://` URI authenticator apps read from a QR code.
+pub fn provisioning_uri(secret: &[u8], email: &str) -> String {
+    let issuer = "unpacking";
+    format!(
+        "otpauth://totp/{issuer}:{email}?secret={}&issuer={issuer}&digits={DIGITS}&period={STEP_SECONDS}",
+        base32(secret)
+    )
+}
+
+pub fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer
+            .iter()
+            .fold(0u64, |bits, byte| bits << 8 | *byte as u64);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Prefixes the sealed secret with its random nonce.
+pub fn encrypt(secret: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+    let mut sealed = secret.to_vec();
+    cipher()
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::empty(),
+            &mut sealed,
+        )
+        .map_err(|e| {
+            error!("AES: {e:?}");
+            Error::InternalServerError
+        })?;
+    Ok([nonce.as_slice(), &sealed].concat())
+}
+
+pub fn decrypt(encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+    if encrypted.len() < NONCE_LEN {
+        return Err(Error::InternalServerError);
+    }
+    let (nonce, sealed) = encrypted.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::InternalServerError)?;
+    let mut sealed = sealed.to_vec();
+    let secret = cipher()
+        .open_in_place(nonce, Aad::empty(), &mut sealed)
+        .map_err(|e| {
+            error!("AES: {e:?}");
+            Error::InternalServerError
+        })?;
+    Ok(secret.to_vec())
+}
+
+/// RFC 4226 HOTP, which TOTP computes for the number of steps since the epoch.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let digest = hmac::sign(&key, &counter.to_be_bytes());
+    let digest = digest.as_ref();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// Debug builds without a configured key generate a temporary one, so secrets enrolled before a
+/// restart can no longer be read and only the recovery codes are left.
+fn cipher() -> &'static LessSafeKey {
+    CIPHER.get_or_init(|| {
+        let key = match totp_encryption_key() {
+            Some(key) => STANDARD
+                .decode(key.trim())
+                .expect("TOTP_ENCRYPTION_KEY must be a base64 encoded 32 byte key"),
+            None if !cfg!(debug_assertions) => panic!("TOTP_ENCRYPTION_KEY must be set"),
+            None => {
+                warn!("TOTP_ENCRYPTION_KEY is not set, encrypting secrets with a temporary key");
+                let mut key = vec![0u8; 32];
+                rand::rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        let key = UnboundKey::new(&AES_256_GCM, &key).expect("Invalid TOTP_ENCRYPTION_KEY");
+        LessSafeKey::new(key)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // https://datatracker.ietf.org/doc/html/rfc6238#appendix-B
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc_test_vectors() {
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (2000000000, "279037"),
+        ] {
+            assert_eq!(verify(SECRET, code, time), Some(time / STEP_SECONDS));
+            assert_eq!(super::code(SECRET, time), code);
+        }
+        assert_eq!(verify(SECRET, "287082", 59 + 2 * STEP_SECONDS), None);
+        assert_eq!(verify(SECRET, "not a code", 59), None);
+    }
+
+    #[test]
+    fn base32_matches_rfc() {
+        // https://datatracker.ietf.org/doc/html/rfc4648#section-10
+        for (bytes, encoded) in [
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ] {
+            assert_eq!(base32(bytes.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn secret_round_trips_encrypted() {
+        let secret = generate_secret();
+        let encrypted = encrypt(&secret).expect("Can not encrypt");
+        assert_ne!(
+            &encrypted[NONCE_LEN..NONCE_LEN + SECRET_LENGTH],
+            secret.as_slice()
+        );
+        assert_eq!(decrypt(&encrypted), Ok(secret));
+    }
+}
@@ -51,6 +51,8 @@ pub struct Forms {
     pub role: String,
     #[serde(rename = "role_admin")]
     pub role_admin: String,
+    #[serde(rename = "role_editor")]
+    pub role_editor: String,
     #[serde(rename = "role_user")]
     pub role_user: String,
     #[serde(rename = "related")]
@@ -64,7 +66,7 @@ pub struct Forms {
 }
 
 impl Forms {
-    pub fn new(authors: String, edit: String, link: String, media: String, media_blog: String, media_book: String, media_news: String, media_twitter: String, media_video: String, media_website: String, name: String, on_length_less_than: String, on_name_empty: String, on_password_empty: String, on_snippet_empty: String, on_username_empty: String, password: String, role: String, role_admin: String, role_user: String, related: String, text: String, terms: String, username: String) -> Forms {
+    pub fn new(authors: String, edit: String, link: String, media: String, media_blog: String, media_book: String, media_news: String, media_twitter: String, media_video: String, media_website: String, name: String, on_length_less_than: String, on_name_empty: String, on_password_empty: String, on_snippet_empty: String, on_username_empty: String, password: String, role: String, role_admin: String, role_editor: String, role_user: String, related: String, text: String, terms: String, username: String) -> Forms {
         Forms {
             authors,
             edit,
@@ -85,6 +87,7 @@ impl Forms {
             password,
             role,
             role_admin,
+            role_editor,
             role_user,
             related,
             text,
@@ -14,6 +14,8 @@
 pub enum Role {
     #[serde(rename = "User")]
     User,
+    #[serde(rename = "Editor")]
+    Editor,
     #[serde(rename = "Admin")]
     Admin,
 
@@ -23,6 +25,7 @@ impl ToString for Role {
     fn to_string(&self) -> String {
         match self {
             Self::User => String::from("User"),
+            Self::Editor => String::from("Editor"),
             Self::Admin => String::from("Admin"),
         }
     }